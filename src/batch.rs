@@ -0,0 +1,151 @@
+use std::io::{Error, ErrorKind, Write, Result};
+
+use iz80::Machine;
+
+use super::emulator::{Emulator, CLOCK_HZ};
+use super::script::{to_cycles, Watcher, POLL_CYCLES};
+use super::serial::MemorySerial;
+
+/*
+Batch execution of RIO commands, to use the MCZ as a build tool. RIO is
+booted and the commands are typed one by one, the run ends after the first
+command that fails. A command is complete when RIO waits for input after
+the prompt, a "%" at the start of a line.
+
+The result is ERCODE, the return code of the last command executed. RIO
+uses 0x80 for a successful completion, the errors have the bit 6 set: 0x4x
+for the command errors, like 0x47 for a nonexistent command or 0x49 for a
+program abort, and 0xCx for the I/O errors. Other codes are not errors,
+STATUS ends with 0x01.
+*/
+
+// Return code of the user programs in the PROM RAM area
+const ERCODE: u16 = 0x13bd;
+
+// Prompt of the PROM monitor
+const PROM_PROMPT: &[u8] = b">";
+
+// RIO prompt, the line feed is followed by a null for the terminal timing
+const PROMPT: &[u8] = b"\n\0%";
+
+// T-states without output to consider RIO waiting at the prompt, 0.5 s
+const IDLE_CYCLES: u64 = CLOCK_HZ / 2;
+
+pub struct Batch {
+    commands: Vec<String>,
+    timeout: f64,
+    boot: bool,
+}
+
+impl Batch {
+    /// ERCODE of a successful command
+    pub const SUCCESS: u8 = 0x80;
+
+    /// Returns true if the ERCODE is an error of RIO.
+    pub fn is_error(ercode: u8) -> bool {
+        ercode & 0x40 != 0
+    }
+
+    /// Exit status for an ERCODE error, clear of the 128 + signal codes of
+    /// the shells: 64 + x for the command errors 4x, 80 + x for the I/O
+    /// errors Cx and 96 for the others.
+    pub fn exit_status(ercode: u8) -> i32 {
+        match ercode & 0xf0 {
+            0x40 => 64 + (ercode & 0x0f) as i32,
+            0xc0 => 80 + (ercode & 0x0f) as i32,
+            _ => 96,
+        }
+    }
+
+    /// Commands to execute, with a time limit in seconds for the whole
+    /// run, boot included.
+    pub fn new(commands: Vec<String>, timeout: f64) -> Batch {
+        Batch {
+            commands,
+            timeout,
+            boot: true,
+        }
+    }
+
+    /// Boots RIO before the commands. Enabled by default, disable it to
+    /// run on a machine restored at the RIO prompt.
+    pub fn set_boot(&mut self, boot: bool) {
+        self.boot = boot;
+    }
+
+    /// Boots RIO and executes the commands on an emulator that has the
+    /// console as serial device. The console output is copied to the
+    /// echo. Returns the ERCODE of the last command executed, the first
+    /// error stops the run. Fails on timeout or if the CPU stops.
+    pub fn run(&self, emulator: &mut Emulator, console: &MemorySerial,
+            echo: &mut dyn Write) -> Result<u8> {
+        let mut watcher = Watcher::new(console, echo);
+        let end = emulator.cycles() + to_cycles(self.timeout);
+
+        if self.boot {
+            // The PROM boots RIO with a carriage return
+            wait_prompt(emulator, console, &mut watcher, end, PROM_PROMPT)?;
+            console.send(b"\r");
+            wait_prompt(emulator, console, &mut watcher, end, PROMPT)?;
+        }
+
+        let mut ercode = Batch::SUCCESS;
+        for command in self.commands.iter() {
+            console.send(command.as_bytes());
+            console.send(b"\r");
+            wait_prompt(emulator, console, &mut watcher, end, PROMPT)?;
+
+            ercode = emulator.machine().peek(ERCODE);
+            if Batch::is_error(ercode) {
+                break;
+            }
+        }
+        Ok(ercode)
+    }
+}
+
+fn wait_prompt(emulator: &mut Emulator, console: &MemorySerial,
+        watcher: &mut Watcher, end: u64, prompt: &[u8]) -> Result<()> {
+    let mut idle = 0;
+    loop {
+        if emulator.is_stopped() {
+            return Err(Error::other("The CPU stopped"));
+        }
+        if emulator.cycles() >= end {
+            return Err(Error::new(ErrorKind::TimedOut, "Timeout"));
+        }
+
+        emulator.run_for(POLL_CYCLES);
+        if watcher.collect()? {
+            idle = 0;
+        } else {
+            idle += POLL_CYCLES;
+        }
+
+        if idle >= IDLE_CYCLES && console.is_input_empty() && watcher.ends_with(prompt) {
+            watcher.clear();
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_have_the_bit_6() {
+        assert!(!Batch::is_error(Batch::SUCCESS));
+        assert!(!Batch::is_error(0x01));
+        assert!(Batch::is_error(0x47));
+        assert!(Batch::is_error(0xc2));
+    }
+
+    #[test]
+    fn exit_status_is_below_the_signals() {
+        assert_eq!(Batch::exit_status(0x47), 71);
+        assert_eq!(Batch::exit_status(0xc2), 82);
+        assert_eq!(Batch::exit_status(0xcf), 95);
+        assert_eq!(Batch::exit_status(0x7f), 96);
+    }
+}
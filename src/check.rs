@@ -0,0 +1,381 @@
+use std::io::Result;
+use std::ops::RangeInclusive;
+
+use super::filesystem::*;
+use super::media::*;
+
+/*
+Consistency check of RIO images. It verifies:
+ - The header of each sector, the sector number with bit 7 set and the
+   track number.
+ - The CRC of each sector. Some images have zeros instead of the CRCs,
+   those are counted apart.
+ - The chains of the directory and of the files. Each sector of a block
+   points back to the previous block, or to the descriptor for the first
+   one, and forward to the next block. RIO leaves the end of chain as the
+   forward pointer of some sectors in the middle of the blocks. The
+   descriptor points back to the directory sector with the entry and
+   forward to the first block.
+ - The allocation map: the sectors allocated but not used by any file are
+   lost, the sectors used by several files are cross-linked, the sectors
+   used must be allocated. And the counts of used and available sectors.
+   The system disks have the unused sectors of the tracks 21 to 23
+   allocated, they are not lost.
+
+The repair rewrites the bad headers and CRCs, the pointers of the chains
+that can be followed and the allocation map. The cross-linked sectors are
+only reported. The lost sectors are kept allocated when a chain is broken,
+they may have the rest of a damaged file.
+*/
+
+const RESERVED_TRACKS: RangeInclusive<usize> = 21..=MAP_TRACK;
+
+pub struct Problem {
+    pub description: String,
+    pub repaired: bool,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Sectors with zeros instead of the CRC, written by the repair
+    pub missing_crcs: usize,
+}
+
+impl Report {
+    /// True if there are no problems left.
+    pub fn is_clean(&self) -> bool {
+        self.problems.iter().all(|problem| problem.repaired)
+    }
+}
+
+/// Checks the image, repairing it if requested.
+pub fn check<D: Disk>(fs: &mut Filesystem<D>, repair: bool) -> Result<Report> {
+    let mut checker = Checker {
+        fs,
+        repair,
+        report: Report::default(),
+        owners: vec![None; TRACK_COUNT * SECTOR_COUNT],
+        broken: false,
+    };
+    checker.check_sectors()?;
+    checker.check_files()?;
+    checker.check_map()?;
+    Ok(checker.report)
+}
+
+struct Checker<'a, D: Disk> {
+    fs: &'a mut Filesystem<D>,
+    repair: bool,
+    report: Report,
+    // File using each sector
+    owners: Vec<Option<String>>,
+    // A chain could not be followed to the end
+    broken: bool,
+}
+
+impl<D: Disk> Checker<'_, D> {
+    fn problem(&mut self, description: String, repaired: bool) {
+        self.report.problems.push(Problem { description, repaired });
+    }
+
+    fn check_sectors(&mut self) -> Result<()> {
+        for track in 0..TRACK_COUNT {
+            for sector in 0..SECTOR_COUNT {
+                let mut data = self.fs.disk().read_sector(track, sector).to_vec();
+                let header = [0x80 | sector as u8, track as u8];
+                let stored_crc = u16::from_be_bytes([data[CRC_OFFSET], data[CRC_OFFSET + 1]]);
+                let crc = sector_crc(&data);
+                let address = Address::new(track, sector);
+
+                if data[..2] != header {
+                    self.problem(format!("{}: header {:02x} {:02x}", show(address), data[0], data[1]), self.repair);
+                } else if stored_crc == 0 && crc != 0 {
+                    self.report.missing_crcs += 1;
+                } else if stored_crc != crc {
+                    self.problem(format!("{}: CRC {:04x}, expected {:04x}", show(address), stored_crc, crc), self.repair);
+                }
+
+                if self.repair && (data[..2] != header || stored_crc != crc) {
+                    data[..2].copy_from_slice(&header);
+                    set_sector_crc(&mut data);
+                    self.fs.disk_mut().write_sector(track, sector, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the sector as used by the file. Returns false if it was used
+    /// by another file.
+    fn mark(&mut self, address: Address, name: &str) -> bool {
+        let i = address.track * SECTOR_COUNT + address.sector;
+        match self.owners[i].clone() {
+            None => {
+                self.owners[i] = Some(name.to_string());
+                true
+            },
+            Some(owner) => {
+                self.problem(format!("{}: cross-linked, used by {} and {}", show(address), owner, name), false);
+                self.broken = true;
+                false
+            },
+        }
+    }
+
+    fn check_files(&mut self) -> Result<()> {
+        for sector in 0..MAP_SECTORS {
+            self.mark(Address::new(MAP_TRACK, sector), "the allocation map");
+        }
+
+        let directory = match self.fs.directory_sectors() {
+            Ok(directory) => directory,
+            Err(err) => {
+                self.problem(format!("Directory: {}", err), false);
+                self.broken = true;
+                return Ok(());
+            },
+        };
+        let mut entries = Vec::new();
+        for &address in directory.iter() {
+            for (name, descriptor) in self.fs.directory_entries(address)? {
+                entries.push((name, descriptor, address));
+            }
+        }
+
+        // The directory is a file, with an entry on some disks
+        let descriptor = Address::new(DIRECTORY_TRACK, 0);
+        let directory_sector = entries.iter()
+            .find(|(_, entry_descriptor, _)| *entry_descriptor == descriptor)
+            .map(|(_, _, address)| *address);
+        self.check_file("DIRECTORY", descriptor, directory_sector)?;
+
+        for (name, descriptor, address) in entries {
+            if descriptor != Address::new(DIRECTORY_TRACK, 0) {
+                self.check_file(&name, descriptor, Some(address))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_file(&mut self, name: &str, descriptor: Address, directory_sector: Option<Address>) -> Result<()> {
+        if !descriptor.is_valid() {
+            self.problem(format!("{}: invalid descriptor address", name), false);
+            self.broken = true;
+            return Ok(());
+        }
+        if !self.mark(descriptor, name) {
+            return Ok(());
+        }
+        let mut file = self.fs.file_entry(name, descriptor)?;
+
+        // Follow the forward pointers, the back pointers must match
+        let block_sectors = file.block_sectors();
+        let mut blocks = Vec::new();
+        let mut previous = descriptor;
+        let mut next = file.first_block;
+        let mut complete = true;
+        let mut bad_pointers = Vec::new();
+        while let Some(block) = next {
+            if !block.is_valid() || block.sector + block_sectors > SECTOR_COUNT {
+                self.problem(format!("{}: invalid block address after {}", name, show(previous)), false);
+                complete = false;
+                break;
+            }
+            if blocks.len() >= file.record_count {
+                self.problem(format!("{}: chain longer than {} records", name, file.record_count), false);
+                complete = false;
+                break;
+            }
+            let forward = self.fs.pointers(block)?.1;
+            for i in 0..block_sectors {
+                let address = Address::new(block.track, block.sector + i);
+                if !self.mark(address, name) {
+                    return Ok(());
+                }
+                let (sector_back, sector_forward) = self.fs.pointers(address)?;
+                if sector_back != Some(previous) || (sector_forward != forward && (i == 0 || sector_forward.is_some())) {
+                    bad_pointers.push((address, previous, forward));
+                }
+            }
+            blocks.push(block);
+            previous = block;
+            next = forward;
+        }
+        if complete && blocks.len() < file.record_count {
+            self.problem(format!("{}: chain ends after {} of {} records",
+                name, blocks.len(), file.record_count), false);
+            complete = false;
+        }
+
+        // Only the complete chains are repaired
+        for (address, back, forward) in bad_pointers {
+            self.problem(format!("{}: pointers of {}", name, show(address)), self.repair && complete);
+            if self.repair && complete {
+                self.fs.set_pointers(address, Some(back), forward)?;
+            }
+        }
+        if !complete {
+            self.broken = true;
+            return Ok(());
+        }
+
+        // Links of the descriptor
+        let mut links_ok = self.fs.pointers(descriptor)?.1 == file.first_block
+            && file.last_block == blocks.last().copied();
+        if let Some(directory_sector) = directory_sector {
+            links_ok &= file.directory_sector == Some(directory_sector)
+                && self.fs.pointers(descriptor)?.0 == Some(directory_sector);
+            file.directory_sector = Some(directory_sector);
+        }
+        if !links_ok {
+            self.problem(format!("{}: links of the descriptor at {}", name, show(descriptor)), self.repair);
+            if self.repair {
+                file.last_block = blocks.last().copied();
+                self.fs.set_links(&file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_map(&mut self) -> Result<()> {
+        let mut map = self.fs.allocation_map()?;
+        let used = map.iter().map(|b| b.count_ones() as usize).sum::<usize>();
+        let mut changed = false;
+        let mut lost = Vec::new();
+        for (i, owner) in self.owners.clone().iter().enumerate() {
+            let address = Address::new(i / SECTOR_COUNT, i % SECTOR_COUNT);
+            let mask = 0x80 >> (i % 8);
+            let allocated = map[i / 8] & mask != 0;
+            match owner {
+                Some(name) if !allocated => {
+                    self.problem(format!("{}: used by {} but free on the map", show(address), name), self.repair);
+                    map[i / 8] |= mask;
+                    changed = true;
+                },
+                None if allocated && !RESERVED_TRACKS.contains(&address.track) => lost.push(i),
+                _ => {},
+            }
+        }
+
+        if !lost.is_empty() {
+            let repaired = self.repair && !self.broken;
+            self.problem(format!("{} sectors allocated but not used, the first at {}", lost.len(),
+                show(Address::new(lost[0] / SECTOR_COUNT, lost[0] % SECTOR_COUNT))), repaired);
+            if repaired {
+                for i in lost {
+                    map[i / 8] &= !(0x80 >> (i % 8));
+                }
+                changed = true;
+            }
+        }
+
+        let (stored_used, stored_available) = self.fs.statistics()?;
+        if stored_used != used || stored_available != TRACK_COUNT * SECTOR_COUNT - used {
+            self.problem(format!("Counts of {} used and {} available sectors, the map has {} used",
+                stored_used, stored_available, used), self.repair);
+            changed = true;
+        }
+
+        if self.repair && changed {
+            self.fs.set_allocation_map(&map)?;
+        }
+        Ok(())
+    }
+}
+
+fn show(address: Address) -> String {
+    format!("Track {} sector {}", address.track, address.sector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_filesystem() -> Filesystem<Media> {
+        let mut media = Media::new_from_bytes(&blank_image());
+        media.write_protected = false;
+        let mut fs = Filesystem::new(media);
+        fs.initialize("TEST DISK").unwrap();
+        let content: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        fs.write_file("DATA", TYPE_BINARY, &content).unwrap();
+        fs.write_file("TEXT", TYPE_ASCII, b"HELLO\r").unwrap();
+        fs
+    }
+
+    fn descriptions(report: &Report) -> Vec<&str> {
+        report.problems.iter().map(|problem| problem.description.as_str()).collect()
+    }
+
+    #[test]
+    fn written_filesystem_is_clean() {
+        let mut fs = new_filesystem();
+        let report = check(&mut fs, false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", descriptions(&report));
+        assert_eq!(report.missing_crcs, 0);
+    }
+
+    #[test]
+    fn bad_header_and_crc_are_repaired() {
+        let mut fs = new_filesystem();
+        let mut data = fs.disk().read_sector(5, 3).to_vec();
+        data[0] = 0x80;
+        fs.disk_mut().write_sector(5, 3, &data).unwrap();
+        let mut data = fs.disk().read_sector(6, 4).to_vec();
+        data[10] ^= 0xff;
+        fs.disk_mut().write_sector(6, 4, &data).unwrap();
+
+        let report = check(&mut fs, false).unwrap();
+        assert_eq!(report.problems.len(), 2, "{:?}", descriptions(&report));
+        assert!(!report.is_clean());
+        assert!(descriptions(&report)[0].starts_with("Track 5 sector 3: header 80 05"));
+
+        let report = check(&mut fs, true).unwrap();
+        assert!(report.is_clean());
+        assert!(check(&mut fs, false).unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn missing_crcs_are_counted() {
+        let mut fs = new_filesystem();
+        let mut data = fs.disk().read_sector(7, 1).to_vec();
+        data[CRC_OFFSET..].copy_from_slice(&[0, 0]);
+        fs.disk_mut().write_sector(7, 1, &data).unwrap();
+
+        let report = check(&mut fs, false).unwrap();
+        assert!(report.problems.is_empty());
+        assert_eq!(report.missing_crcs, 1);
+    }
+
+    #[test]
+    fn bad_pointers_are_repaired() {
+        let mut fs = new_filesystem();
+        let file = fs.find("DATA").unwrap();
+        let blocks = fs.blocks(&file).unwrap();
+        fs.set_pointers(blocks[3], Some(blocks[0]), Some(blocks[4])).unwrap();
+
+        let report = check(&mut fs, false).unwrap();
+        assert_eq!(descriptions(&report), [format!("DATA: pointers of {}", show(blocks[3]))]);
+
+        let content = fs.read_file(&file).unwrap();
+        assert!(check(&mut fs, true).unwrap().is_clean());
+        assert!(check(&mut fs, false).unwrap().problems.is_empty());
+        assert_eq!(fs.pointers(blocks[3]).unwrap(), (Some(blocks[2]), Some(blocks[4])));
+        assert_eq!(fs.read_file(&file).unwrap(), content);
+    }
+
+    #[test]
+    fn lost_sectors_are_freed() {
+        let mut fs = new_filesystem();
+        let used = fs.used_sectors().unwrap();
+        let mut map = fs.allocation_map().unwrap();
+        map[(40 * SECTOR_COUNT) / 8] |= 0x80;
+        fs.set_allocation_map(&map).unwrap();
+
+        let report = check(&mut fs, false).unwrap();
+        assert_eq!(descriptions(&report), ["1 sectors allocated but not used, the first at Track 40 sector 0"]);
+        assert!(check(&mut fs, true).unwrap().is_clean());
+        assert!(check(&mut fs, false).unwrap().problems.is_empty());
+        assert_eq!(fs.used_sectors().unwrap(), used);
+    }
+}
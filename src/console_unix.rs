@@ -1,30 +1,64 @@
-use std::io::{Read, stdin, Write, stdout};
+use std::collections::VecDeque;
+use std::io::{BufRead, Read, Result, stdin, Write, stdout};
 use std::thread;
 use std::time::Duration;
 
 use termios::*;
 
+use super::serial::SerialDevice;
+
 const STDIN_FD: i32 = 0;
 
 pub struct Console {
     initial_termios: Option<Termios>,
-    next_char: Option<u8>
+    buffer: VecDeque<u8>,
+    break_key: Option<u8>,
+    break_pressed: bool,
+    debug_key: Option<u8>,
+    debug_pressed: bool,
 }
 
 impl Console {
-    pub fn new() -> Console {
+    pub fn new(break_key: Option<u8>) -> Console {
         // Prepare terminal
         let initial_termios = Termios::from_fd(STDIN_FD).ok();
 
         let c = Console {
             initial_termios,
-            next_char: None,
+            buffer: VecDeque::new(),
+            break_key,
+            break_pressed: false,
+            debug_key: None,
+            debug_pressed: false,
         };
 
         c.setup_host_terminal(false);
         c
     }
 
+    /// Sets the host key to enter the debugger, none by default.
+    pub fn set_debug_key(&mut self, debug_key: Option<u8>) {
+        self.debug_key = debug_key;
+    }
+
+    /// Reads a line with echo and line editing, for the prompt of the
+    /// debugger. Returns None at the end of the input.
+    pub fn read_line() -> Result<Option<String>> {
+        let console_termios = Termios::from_fd(STDIN_FD).ok();
+        if let Some(mut line_mode) = console_termios {
+            line_mode.c_iflag |= ICRNL;
+            line_mode.c_lflag |= ECHO | ICANON;
+            line_mode.c_cc[VMIN] = 1;
+            tcsetattr(STDIN_FD, TCSANOW, &line_mode)?;
+        }
+        let mut line = String::new();
+        let size = stdin().lock().read_line(&mut line);
+        if let Some(console_termios) = console_termios {
+            tcsetattr(STDIN_FD, TCSANOW, &console_termios)?;
+        }
+        Ok(if size? == 0 {None} else {Some(line.trim_end().to_string())})
+    }
+
     fn setup_host_terminal(&self, blocking: bool) {
         if let Some(mut initial) = self.initial_termios {
             initial.c_iflag &= !(IXON | ICRNL);
@@ -35,37 +69,62 @@ impl Console {
         }
     }
 
-    pub fn status(&mut self) -> bool {
-        match self.next_char {
-            Some(_) => true,
-            None => {
-                let mut buf = [0];
-                let size = stdin().read(&mut buf).unwrap_or(0);
-                if size != 0 {
-                    self.next_char = Some(buf[0]);
-                    true
-                } else {
-                    // Avoid 100% CPU usage waiting for input.
-                    thread::sleep(Duration::from_nanos(100));
-                    false
-                }
+    fn receive(&mut self, ch: u8) {
+        if Some(ch) == self.break_key {
+            self.break_pressed = true;
+        } else if Some(ch) == self.debug_key {
+            self.debug_pressed = true;
+        } else {
+            self.buffer.push_back(ch);
+        }
+    }
+
+    fn poll(&mut self) {
+        let mut buf = [0; 16];
+        let size = stdin().read(&mut buf).unwrap_or(0);
+        for &ch in buf[..size].iter() {
+            self.receive(ch);
+        }
+    }
+}
+
+impl SerialDevice for Console {
+    fn status(&mut self) -> bool {
+        if self.buffer.is_empty() {
+            self.poll();
+            if self.buffer.is_empty() {
+                // Avoid 100% CPU usage waiting for input.
+                thread::sleep(Duration::from_nanos(100));
+                return false;
             }
         }
+        true
+    }
+
+    /// Returns true once after the break key is pressed.
+    fn break_pressed(&mut self) -> bool {
+        self.poll();
+        std::mem::replace(&mut self.break_pressed, false)
+    }
+
+    /// Returns true once after the debugger key is pressed.
+    fn debug_pressed(&mut self) -> bool {
+        self.poll();
+        std::mem::replace(&mut self.debug_pressed, false)
     }
 
-    pub fn read(&mut self) -> u8 {
-        let ch = match self.next_char {
-            Some(ch) => {
-                self.next_char = None;
-                ch
-            },
+    fn read(&mut self) -> u8 {
+        let ch = match self.buffer.pop_front() {
+            Some(ch) => ch,
             None => {
                 // Blocks waiting for char
                 self.setup_host_terminal(true);
                 let mut buf = [0];
                 stdin().read_exact(&mut buf).unwrap();
                 self.setup_host_terminal(false);
-                buf[0]
+                self.receive(buf[0]);
+                // A break returns a null char to let the NMI be serviced
+                self.buffer.pop_front().unwrap_or(0)
             }
         };
 
@@ -75,7 +134,7 @@ impl Console {
         }
     }
 
-    pub fn put(&mut self, ch: u8) {
+    fn write(&mut self, ch: u8) {
         print!("{}", ch as char);
         stdout().flush().unwrap();
     }
@@ -1,4 +1,6 @@
-use std::io::{Write, stdout};
+use std::collections::VecDeque;
+use std::io::{stdin, Write, Result, stdout};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use crossterm::terminal;
@@ -6,52 +8,105 @@ use crossterm::event;
 use crossterm::queue;
 use crossterm::style;
 
+use super::serial::SerialDevice;
+
+// The raw mode is enabled while there is a console
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
 pub struct Console {
-    next_char: Option<u8>,
+    buffer: VecDeque<u8>,
+    break_key: Option<u8>,
+    break_pressed: bool,
+    debug_key: Option<u8>,
+    debug_pressed: bool,
 }
 
 impl Console {
-    pub fn new() -> Console {
+    pub fn new(break_key: Option<u8>) -> Console {
         terminal::enable_raw_mode().unwrap();
+        RAW_MODE.store(true, Ordering::Relaxed);
 
         Console {
-            next_char: None,
+            buffer: VecDeque::new(),
+            break_key,
+            break_pressed: false,
+            debug_key: None,
+            debug_pressed: false,
         }
     }
 
-    pub fn status(&mut self) -> bool {
-        match self.next_char {
-            Some(_) => true,
-            None => {
-                loop {
-                    if event::poll(Duration::from_nanos(100)).unwrap() {
-                        let event = event::read().unwrap();
-                        let some_ch = event_to_char(event);
-                        if let Some(ch) = some_ch {
-                            self.next_char = Some(ch);
-                            break true
-                        }
-                        // The event is not a valid char, ignore and retry
-                    } else {
-                        break false
-                    }
-                }
+    /// Sets the host key to enter the debugger, none by default.
+    pub fn set_debug_key(&mut self, debug_key: Option<u8>) {
+        self.debug_key = debug_key;
+    }
+
+    /// Reads a line with echo and line editing, for the prompt of the
+    /// debugger. Returns None at the end of the input.
+    pub fn read_line() -> Result<Option<String>> {
+        let raw = RAW_MODE.load(Ordering::Relaxed);
+        if raw {
+            terminal::disable_raw_mode().unwrap();
+        }
+        let mut line = String::new();
+        let size = stdin().read_line(&mut line);
+        if raw {
+            terminal::enable_raw_mode().unwrap();
+        }
+        Ok(if size? == 0 {None} else {Some(line.trim_end().to_string())})
+    }
+
+    fn receive(&mut self, ch: u8) {
+        if Some(ch) == self.break_key {
+            self.break_pressed = true;
+        } else if Some(ch) == self.debug_key {
+            self.debug_pressed = true;
+        } else {
+            self.buffer.push_back(ch);
+        }
+    }
+
+    fn poll(&mut self) {
+        while event::poll(Duration::from_nanos(100)).unwrap() {
+            let event = event::read().unwrap();
+            if let Some(ch) = event_to_char(event) {
+                self.receive(ch);
             }
+            // The event is not a valid char, ignore and retry
         }
     }
+}
 
-    pub fn read(&mut self) -> u8 {
-        let ch = match self.next_char {
-            Some(ch) => {
-                self.next_char = None;
-                ch
-            },
+impl SerialDevice for Console {
+    fn status(&mut self) -> bool {
+        if self.buffer.is_empty() {
+            self.poll();
+        }
+        !self.buffer.is_empty()
+    }
+
+    /// Returns true once after the break key is pressed.
+    fn break_pressed(&mut self) -> bool {
+        self.poll();
+        std::mem::replace(&mut self.break_pressed, false)
+    }
+
+    /// Returns true once after the debugger key is pressed.
+    fn debug_pressed(&mut self) -> bool {
+        self.poll();
+        std::mem::replace(&mut self.debug_pressed, false)
+    }
+
+    fn read(&mut self) -> u8 {
+        let ch = match self.buffer.pop_front() {
+            Some(ch) => ch,
             None => {
                 loop {
                     let event = event::read().unwrap();
                     let some_ch = event_to_char(event);
                     if let Some(ch) = some_ch {
-                        break ch;
+                        self.receive(ch);
+                        // A break returns a null char to let the NMI be serviced
+                        break self.buffer.pop_front().unwrap_or(0);
                     }
                     // The event is not a valid char, ignore and retry
                 }
@@ -64,7 +119,7 @@ impl Console {
         }
     }
 
-    pub fn put(&mut self, ch: u8) {
+    fn write(&mut self, ch: u8) {
             queue!(stdout(), style::Print(ch as char)).unwrap();
             stdout().flush().unwrap();
     }
@@ -73,6 +128,7 @@ impl Console {
 impl Drop for Console {
     fn drop(&mut self) {
         terminal::disable_raw_mode().unwrap();
+        RAW_MODE.store(false, Ordering::Relaxed);
     }
 }
 
@@ -92,6 +148,9 @@ fn event_to_char(event: event::Event) -> Option<u8> {
                     if '`' <= c && c <= '~' {
                         // Valid control range
                         Some(c as u8 - '`' as u8)
+                    } else if '@' <= c && c <= '_' {
+                        // Control with symbols, like ^] or ^\
+                        Some(c as u8 - '@' as u8)
                     } else {
                         None
                     }
@@ -0,0 +1,196 @@
+use std::io::{BufRead, BufReader, Write, Result};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use super::mdc::MAX_DRIVES;
+
+/*
+Control channel of the emulator, a TCP server with a command per line:
+    list: shows the disk on each drive.
+    eject DRIVE: removes the disk, the drive is not ready until a disk is
+        inserted.
+    insert DRIVE IMAGE: inserts an image file, or a host directory, on an
+        empty drive.
+    save FILE: saves a snapshot of the machine.
+The files are relative to the current directory of the emulator, without
+"..".
+    help: shows the commands.
+Each command gets a reply of one or more lines. Several clients can be
+connected, the emulator executes the commands in order between
+instructions.
+*/
+
+const HELP: &str = "Commands:
+  list                 Shows the disk on each drive
+  eject DRIVE          Removes the disk of the drive
+  insert DRIVE IMAGE   Inserts an image or a host directory on an empty drive
+  save FILE            Saves a snapshot of the machine
+The files are relative to the current directory";
+
+pub enum Command {
+    List,
+    Eject(usize),
+    Insert(usize, String),
+    Save(String),
+}
+
+impl Command {
+    /// Parses a command line, the error is the reply to the client.
+    pub fn parse(line: &str) -> std::result::Result<Command, String> {
+        let (name, arguments) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim_start()),
+            None => (line, ""),
+        };
+        let (drive, image) = match arguments.find(char::is_whitespace) {
+            Some(i) => (&arguments[..i], arguments[i..].trim_start()),
+            None => (arguments, ""),
+        };
+        let drive = || match drive.parse::<usize>() {
+            Ok(drive) if drive < MAX_DRIVES => Ok(drive),
+            _ => Err(format!("Invalid drive '{}', the drives are 0 to {}", drive, MAX_DRIVES - 1)),
+        };
+
+        match name.to_ascii_lowercase().as_str() {
+            "list" if arguments.is_empty() => Ok(Command::List),
+            "eject" if image.is_empty() => Ok(Command::Eject(drive()?)),
+            "insert" if !image.is_empty() => {
+                let drive = drive()?;
+                Ok(Command::Insert(drive, relative_file(image)?))
+            },
+            "save" if !arguments.is_empty() => Ok(Command::Save(relative_file(arguments)?)),
+            "list" | "eject" | "insert" | "save" => Err(format!("Invalid arguments for {}\n{}", name, HELP)),
+            "help" => Err(HELP.to_string()),
+            _ => Err(format!("Unknown command {}\n{}", name, HELP)),
+        }
+    }
+}
+
+/// Checks that a file is relative to the current directory, without "..".
+/// The clients can't read or write anywhere on the host.
+fn relative_file(file: &str) -> std::result::Result<String, String> {
+    let relative = Path::new(file).components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if relative {
+        Ok(file.to_string())
+    } else {
+        Err(format!("Invalid file {}, it has to be relative to the current directory, without ..", file))
+    }
+}
+
+/// Command received, to be executed by the emulator.
+pub struct Request {
+    pub command: Command,
+    reply: Sender<String>,
+}
+
+impl Request {
+    /// Sends the reply to the client.
+    pub fn reply(&self, text: &str) {
+        let _ = self.reply.send(text.to_string());
+    }
+}
+
+pub struct Control {
+    requests: Receiver<Request>,
+}
+
+impl Control {
+    /// Listens for clients on the address, like "127.0.0.1:2324".
+    pub fn listen(address: &str) -> Result<Control> {
+        let listener = TcpListener::bind(address)?;
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                thread::spawn(move || serve(stream, sender));
+            }
+        });
+
+        Ok(Control {
+            requests: receiver,
+        })
+    }
+
+    /// Next command received, if any.
+    pub fn poll(&self) -> Option<Request> {
+        self.requests.try_recv().ok()
+    }
+}
+
+/// Reads the commands of a client, waiting for each reply. Ends when the
+/// client disconnects.
+fn serve(stream: TcpStream, requests: Sender<Request>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = match Command::parse(line) {
+            Ok(command) => {
+                let (sender, receiver) = channel();
+                if requests.send(Request { command, reply: sender }).is_err() {
+                    break;
+                }
+                match receiver.recv() {
+                    Ok(reply) => reply,
+                    Err(_) => break,
+                }
+            },
+            Err(message) => message,
+        };
+        writer.write_all(reply.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert!(matches!(Command::parse("list"), Ok(Command::List)));
+        assert!(matches!(Command::parse("EJECT 1"), Ok(Command::Eject(1))));
+        assert!(matches!(Command::parse("insert 7  disks/MY DISK.MCZ"),
+            Ok(Command::Insert(7, image)) if image == "disks/MY DISK.MCZ"));
+    }
+
+    #[test]
+    fn parse_checks_the_drive() {
+        assert!(Command::parse(&format!("eject {}", MAX_DRIVES)).err().unwrap().starts_with("Invalid drive"));
+        assert!(Command::parse("eject A").err().unwrap().starts_with("Invalid drive"));
+        assert!(Command::parse("insert -1 EMPTY.MCZ").err().unwrap().starts_with("Invalid drive"));
+    }
+
+    #[test]
+    fn parse_checks_the_arguments() {
+        assert!(Command::parse("list 1").err().unwrap().starts_with("Invalid arguments for list"));
+        assert!(Command::parse("eject").is_err());
+        assert!(Command::parse("insert 1").err().unwrap().starts_with("Invalid arguments for insert"));
+        assert!(Command::parse("format 1").err().unwrap().starts_with("Unknown command"));
+    }
+
+    #[test]
+    fn save_is_relative_to_the_current_directory() {
+        assert!(matches!(Command::parse("save snapshots/RIO.SNAP"),
+            Ok(Command::Save(file)) if file == "snapshots/RIO.SNAP"));
+        assert!(matches!(Command::parse("save ./RIO.SNAP"), Ok(Command::Save(_))));
+        for file in ["/tmp/RIO.SNAP", "../RIO.SNAP", "snapshots/../../RIO.SNAP"] {
+            let err = Command::parse(&format!("save {}", file)).err().unwrap();
+            assert!(err.starts_with("Invalid file"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn insert_is_relative_to_the_current_directory() {
+        for image in ["/etc/passwd", "../RIO.MCZ", "disks/../../RIO.MCZ"] {
+            let err = Command::parse(&format!("insert 1 {}", image)).err().unwrap();
+            assert!(err.starts_with("Invalid file"), "{}", err);
+        }
+    }
+}
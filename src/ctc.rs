@@ -0,0 +1,337 @@
+use std::io::Result;
+
+use super::interrupts::InterruptDevice;
+use super::snapshot::{Reader, Writer};
+
+/*
+Z80-CTC counter timer circuit, ports 0xD4 to 0xD7.
+
+Channel usage on the MCZ-1:
+    CLK0: floppy timing, counts the sector pulses of the disk
+    CLK1: baud rate generator for the USART
+    CLK2: real time clock, user defined
+    CLK3: BRKPRT, used by the PROM to trace single instructions
+
+Control word:
+    bit 0: 1 for a control word, 0 for the interrupt vector
+    bit 1: software reset, the channel stops counting
+    bit 2: the time constant follows
+    bit 3: timer mode, 0 to start automatically, 1 to start on a CLK/TRG pulse
+    bit 4: CLK/TRG edge selection, ignored
+    bit 5: timer mode prescaler, 0 for 16, 1 for 256
+    bit 6: 0 for timer mode, 1 for counter mode
+    bit 7: interrupt enable
+*/
+
+const CONTROL: u8 = 0x01;
+const RESET: u8 = 0x02;
+const TIME_CONSTANT: u8 = 0x04;
+const TRIGGER: u8 = 0x08;
+const PRESCALER_256: u8 = 0x20;
+const COUNTER_MODE: u8 = 0x40;
+const INTERRUPT: u8 = 0x80;
+
+// The MCZ-1 forces bits 6, 3 and 2 high when reading CLK3. RIO checks
+// them to detect the hardware.
+const CLK3_ID_BITS: u8 = 0x4c;
+
+#[derive(Default)]
+struct Channel {
+    control: u8,
+    time_constant: u8,
+    counter: u16,
+    prescaler: u32,
+    running: bool,
+    waiting_time_constant: bool,
+    waiting_trigger: bool,
+    int_pending: bool,
+    int_in_service: bool,
+}
+
+impl Channel {
+    fn reload(&mut self) {
+        self.counter = if self.time_constant == 0 {256} else {self.time_constant as u16};
+    }
+
+    fn write(&mut self, value: u8) {
+        if self.waiting_time_constant {
+            self.waiting_time_constant = false;
+            self.time_constant = value;
+            if !self.running {
+                self.reload();
+                self.prescaler = 0;
+                if self.control & COUNTER_MODE == 0 && self.control & TRIGGER != 0 {
+                    self.waiting_trigger = true;
+                } else {
+                    self.running = true;
+                }
+            }
+        } else {
+            self.control = value;
+            if value & RESET != 0 {
+                self.running = false;
+                self.waiting_trigger = false;
+            }
+            if value & INTERRUPT == 0 {
+                self.int_pending = false;
+            }
+            self.waiting_time_constant = value & TIME_CONSTANT != 0;
+        }
+    }
+
+    fn count(&mut self, count: u32) {
+        for _ in 0..count {
+            self.counter -= 1;
+            if self.counter == 0 {
+                // Zero count, the time constant is reloaded
+                self.reload();
+                if self.control & INTERRUPT != 0 {
+                    self.int_pending = true;
+                }
+            }
+        }
+    }
+
+    fn advance(&mut self, cycles: u32) {
+        if !self.running || self.control & COUNTER_MODE != 0 {
+            return;
+        }
+        let prescaler = if self.control & PRESCALER_256 != 0 {256} else {16};
+        self.prescaler += cycles;
+        let count = self.prescaler / prescaler;
+        self.prescaler %= prescaler;
+        self.count(count);
+    }
+
+    fn save_state(&self, writer: &mut Writer) {
+        writer.u8(self.control);
+        writer.u8(self.time_constant);
+        writer.u16(self.counter);
+        writer.u32(self.prescaler);
+        writer.bool(self.running);
+        writer.bool(self.waiting_time_constant);
+        writer.bool(self.waiting_trigger);
+        writer.bool(self.int_pending);
+        writer.bool(self.int_in_service);
+    }
+
+    fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        self.control = reader.u8()?;
+        self.time_constant = reader.u8()?;
+        self.counter = reader.u16()?;
+        self.prescaler = reader.u32()?;
+        self.running = reader.bool()?;
+        self.waiting_time_constant = reader.bool()?;
+        self.waiting_trigger = reader.bool()?;
+        self.int_pending = reader.bool()?;
+        self.int_in_service = reader.bool()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, pulses: u32) {
+        if pulses == 0 {
+            return;
+        }
+        if self.waiting_trigger {
+            self.waiting_trigger = false;
+            self.running = true;
+        } else if self.running && self.control & COUNTER_MODE != 0 {
+            self.count(pulses);
+        }
+    }
+}
+
+pub struct Ctc {
+    channels: [Channel; 4],
+    vector: u8,
+}
+
+impl Ctc {
+    pub fn new() -> Ctc {
+        Ctc {
+            channels: Default::default(),
+            vector: 0,
+        }
+    }
+
+    pub fn port_in(&mut self, channel: usize) -> u8 {
+        let value = self.channels[channel].counter as u8;
+        if channel == 3 {
+            value | CLK3_ID_BITS
+        } else {
+            value
+        }
+    }
+
+    pub fn port_out(&mut self, index: usize, value: u8) {
+        let channel = &mut self.channels[index];
+        if !channel.waiting_time_constant && value & CONTROL == 0 {
+            // The vector is loaded only through channel 0, ignored on the others
+            if index == 0 {
+                self.vector = value & 0xf8;
+            }
+        } else {
+            channel.write(value);
+        }
+    }
+
+    /// Advances the timers with the system clock.
+    pub fn advance(&mut self, cycles: u32) {
+        for channel in self.channels.iter_mut() {
+            channel.advance(cycles);
+        }
+    }
+
+    /// Pulses on the CLK/TRG input of a channel.
+    pub fn trigger(&mut self, channel: usize, pulses: u32) {
+        self.channels[channel].trigger(pulses);
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut Writer) {
+        for channel in self.channels.iter() {
+            channel.save_state(writer);
+        }
+        writer.u8(self.vector);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        for channel in self.channels.iter_mut() {
+            channel.load_state(reader)?;
+        }
+        self.vector = reader.u8()?;
+        Ok(())
+    }
+}
+
+/// Channel 0 has the highest priority. A channel under service blocks
+/// the interrupts of itself and the channels with lower priority.
+impl InterruptDevice for Ctc {
+    fn int_pending(&self) -> bool {
+        for channel in self.channels.iter() {
+            if channel.int_in_service {
+                return false;
+            }
+            if channel.int_pending {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn int_in_service(&self) -> bool {
+        self.channels.iter().any(|c| c.int_in_service)
+    }
+
+    fn int_ack(&mut self) -> u8 {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            if channel.int_pending {
+                channel.int_pending = false;
+                channel.int_in_service = true;
+                return self.vector | (i as u8) << 1;
+            }
+        }
+        self.vector
+    }
+
+    fn reti(&mut self) {
+        if let Some(channel) = self.channels.iter_mut().find(|c| c.int_in_service) {
+            channel.int_in_service = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::InterruptDevice;
+
+    const TIMER_INTERRUPT: u8 = INTERRUPT | TIME_CONSTANT | RESET | CONTROL;
+
+    #[test]
+    fn timer_prescaler() {
+        let mut ctc = Ctc::new();
+        ctc.port_out(1, TIMER_INTERRUPT);
+        ctc.port_out(1, 10);
+        ctc.advance(16 * 10 - 1);
+        assert!(!ctc.int_pending());
+        ctc.advance(1);
+        assert!(ctc.int_pending());
+
+        let mut ctc = Ctc::new();
+        ctc.port_out(2, TIMER_INTERRUPT | PRESCALER_256);
+        ctc.port_out(2, 2);
+        ctc.advance(256);
+        assert_eq!(ctc.port_in(2), 1);
+        ctc.advance(255);
+        assert!(!ctc.int_pending());
+        ctc.advance(1);
+        assert!(ctc.int_pending());
+        assert_eq!(ctc.port_in(2), 2);
+    }
+
+    #[test]
+    fn counter_and_trigger_modes() {
+        let mut ctc = Ctc::new();
+        ctc.port_out(0, TIMER_INTERRUPT | COUNTER_MODE);
+        ctc.port_out(0, 3);
+        ctc.advance(10000);
+        assert_eq!(ctc.port_in(0), 3);
+        ctc.trigger(0, 2);
+        assert!(!ctc.int_pending());
+        ctc.trigger(0, 1);
+        assert!(ctc.int_pending());
+
+        let mut ctc = Ctc::new();
+        ctc.port_out(1, TIMER_INTERRUPT | TRIGGER);
+        ctc.port_out(1, 1);
+        ctc.advance(1000);
+        assert!(!ctc.int_pending());
+        ctc.trigger(1, 1);
+        ctc.advance(16);
+        assert!(ctc.int_pending());
+    }
+
+    #[test]
+    fn vector_only_on_channel_0() {
+        let mut ctc = Ctc::new();
+        ctc.port_out(0, 0x40);
+        ctc.port_out(2, 0x80);
+        ctc.port_out(2, TIMER_INTERRUPT);
+        ctc.port_out(2, 1);
+        ctc.advance(16);
+        assert_eq!(ctc.int_ack(), 0x44);
+    }
+
+    #[test]
+    fn daisy_chain_priority() {
+        let mut ctc = Ctc::new();
+        ctc.port_out(0, 0x10);
+        for channel in [0, 2] {
+            ctc.port_out(channel, TIMER_INTERRUPT);
+            ctc.port_out(channel, 1);
+        }
+        ctc.advance(16);
+
+        // Channel 0 first, it blocks channel 2 until RETI
+        assert_eq!(ctc.int_ack(), 0x10);
+        assert!(ctc.int_in_service());
+        assert!(!ctc.int_pending());
+        ctc.reti();
+        assert!(ctc.int_pending());
+        assert_eq!(ctc.int_ack(), 0x14);
+
+        // A channel with higher priority interrupts channel 2
+        ctc.advance(16);
+        assert!(ctc.int_pending());
+        assert_eq!(ctc.int_ack(), 0x10);
+        ctc.reti();
+        ctc.reti();
+        assert!(!ctc.int_in_service());
+    }
+
+    #[test]
+    fn clk3_has_the_id_bits() {
+        let mut ctc = Ctc::new();
+        assert_eq!(ctc.port_in(3) & CLK3_ID_BITS, CLK3_ID_BITS);
+    }
+}
@@ -0,0 +1,681 @@
+use iz80::*;
+
+use super::emulator::Emulator;
+use super::mcz_machine::{Access, MczMachine};
+use super::snapshot::exchange;
+
+/*
+Debugger of the emulator, independent of the monitor of the PROM. It is
+checked after each instruction and stops on:
+    PC breakpoints, before the instruction is executed.
+    memory watchpoints, after an instruction reads or writes a range. The
+        instruction fetches are not reads.
+    port breakpoints, after an IN or an OUT to a port.
+    the end of a step, of a step over a CALL, RST or block instruction, or
+        of a step out of the current subroutine.
+The host user interface shows the state and reads commands when stopped.
+The numbers are hex, like in the monitor.
+*/
+
+const HELP: &str = "Commands, the numbers are hex:
+  c                      Continues the execution
+  s [COUNT]              Executes one or COUNT instructions
+  o                      Steps over a CALL, RST or block instruction
+  out                    Runs until the current subroutine returns
+  r [REGISTER VALUE]     Shows the registers, or changes one, like r hl 4000 or r bc' 0
+  m ADDRESS [COUNT]      Shows the memory
+  e ADDRESS BYTE...      Writes bytes to the memory
+  d [ADDRESS] [COUNT]    Disassembles, from PC by default
+  b ADDRESS              Stops before executing the address
+  w START[-END] [r|w|rw] Stops after a read or write of the range, write by default
+  p PORT [in|out|io]     Stops after an IN or OUT of the port, both by default
+  l                      Lists the breakpoints
+  del [NUMBER]           Deletes a breakpoint, or all of them
+  q                      Quits the emulator";
+
+// Instructions shown by default when disassembling
+const DISASSEMBLY_LINES: u16 = 16;
+// Bytes shown by default when dumping the memory
+const DUMP_BYTES: u16 = 0x40;
+
+#[derive(Clone, Copy)]
+enum Breakpoint {
+    Pc(u16),
+    Memory { start: u16, end: u16, read: bool, write: bool },
+    Port { port: u8, input: bool, output: bool },
+}
+
+enum Mode {
+    Run,
+    Stop,
+    Step(u32),
+    // Until PC is the instruction after the one stepped over, with the
+    // stack back to SP
+    Over { pc: u16, sp: u16 },
+    // Until a return that pops the stack above SP
+    Out { sp: u16 },
+}
+
+/// What the user interface does after a command.
+#[derive(PartialEq, Eq)]
+pub enum Action {
+    Prompt,
+    Resume,
+    Quit,
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    reason: String,
+    // State before the instruction being executed
+    pc: u16,
+    sp: u16,
+    length: u16,
+    returning: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            mode: Mode::Run,
+            reason: String::new(),
+            pc: 0,
+            sp: 0,
+            length: 0,
+            returning: false,
+        }
+    }
+
+    /// Stops on the next check, like with the debugger key.
+    pub fn stop(&mut self, reason: &str) {
+        self.mode = Mode::Stop;
+        self.reason = reason.to_string();
+    }
+
+    /// Checks the instruction just executed, returns true if the debugger
+    /// stops.
+    pub fn check(&mut self, emulator: &mut Emulator) -> bool {
+        if matches!(self.mode, Mode::Run) && self.breakpoints.is_empty() {
+            return false;
+        }
+        if let Some(reason) = self.hit(emulator) {
+            self.stop(&reason);
+        }
+        self.capture(emulator);
+        matches!(self.mode, Mode::Stop)
+    }
+
+    fn hit(&mut self, emulator: &mut Emulator) -> Option<String> {
+        if matches!(self.mode, Mode::Stop) {
+            return Some(self.reason.clone());
+        }
+
+        let registers = emulator.cpu().registers();
+        let pc = registers.pc();
+        let sp = registers.get16(Reg16::SP);
+        for access in emulator.machine().take_accesses() {
+            if let Some(reason) = self.access_hit(access) {
+                return Some(reason);
+            }
+        }
+        for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+            // A repeating instruction, or a HALT, stops only once
+            if let Breakpoint::Pc(address) = *breakpoint {
+                if address == pc && pc != self.pc {
+                    return Some(format!("Breakpoint {} at {:04X}", i + 1, pc));
+                }
+            }
+        }
+
+        match self.mode {
+            Mode::Step(count) if count > 1 => self.mode = Mode::Step(count - 1),
+            Mode::Step(_) => return Some(String::new()),
+            Mode::Over { pc: end, sp: base } if pc == end && sp >= base => return Some(String::new()),
+            Mode::Out { sp: base } if self.returning && sp > self.sp && self.sp >= base =>
+                return Some(format!("Returned to {:04X}", pc)),
+            _ => {},
+        }
+        None
+    }
+
+    fn access_hit(&self, access: Access) -> Option<String> {
+        let (description, address, port) = match access {
+            // Only the reads outside the instruction
+            Access::Read(address, _) if address.wrapping_sub(self.pc) < self.length => return None,
+            Access::Read(address, value) => (format!("read {:02X} from {:04X}", value, address), Some((address, false)), None),
+            Access::Write(address, value) => (format!("write {:02X} to {:04X}", value, address), Some((address, true)), None),
+            Access::In(port, value) => (format!("IN {:02X} from port {:02X}", value, port), None, Some((port, false))),
+            Access::Out(port, value) => (format!("OUT {:02X} to port {:02X}", value, port), None, Some((port, true))),
+        };
+        let index = self.breakpoints.iter().position(|breakpoint| match *breakpoint {
+            Breakpoint::Memory { start, end, read, write } => match address {
+                Some((address, is_write)) => start <= address && address <= end
+                    && if is_write {write} else {read},
+                None => false,
+            },
+            Breakpoint::Port { port: number, input, output } => match port {
+                Some((port, is_output)) => port == number && if is_output {output} else {input},
+                None => false,
+            },
+            Breakpoint::Pc(_) => false,
+        })?;
+        let kind = if address.is_some() {"Watchpoint"} else {"Port breakpoint"};
+        Some(format!("{} {}: {} at {:04X}", kind, index + 1, description, self.pc))
+    }
+
+    /// Saves the state before the next instruction.
+    fn capture(&mut self, emulator: &mut Emulator) {
+        let registers = emulator.cpu().registers();
+        self.pc = registers.pc();
+        self.sp = registers.get16(Reg16::SP);
+        let machine = emulator.machine();
+        self.returning = matches!(self.mode, Mode::Out {..}) && is_return(machine, self.pc);
+        self.length = instruction_length(machine, self.pc);
+        // The peeks of the debugger are not accesses of the program
+        machine.take_accesses();
+    }
+
+    fn resume(&mut self, emulator: &mut Emulator, mode: Mode) -> Action {
+        self.mode = mode;
+        self.capture(emulator);
+        Action::Resume
+    }
+
+    /// Shows why the debugger stopped, the registers and the instruction.
+    pub fn status(&mut self, emulator: &mut Emulator) -> String {
+        let mut lines = Vec::new();
+        if !self.reason.is_empty() {
+            lines.push(std::mem::take(&mut self.reason));
+        }
+        lines.push(registers(emulator));
+        let pc = emulator.cpu().registers().pc();
+        lines.push(disassembly_line(emulator.machine(), pc).0);
+        lines.join("\n")
+    }
+
+    /// Executes a command line, returns the action and the text to show.
+    pub fn command(&mut self, emulator: &mut Emulator, line: &str) -> (Action, String) {
+        match self.execute(emulator, line) {
+            Ok(result) => result,
+            Err(message) => (Action::Prompt, message),
+        }
+    }
+
+    fn execute(&mut self, emulator: &mut Emulator, line: &str) -> Result<(Action, String), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let name = match words.first() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Ok((Action::Prompt, String::new())),
+        };
+        let arguments = &words[1..];
+        let argument = |i: usize| arguments.get(i).copied()
+            .ok_or_else(|| format!("Missing arguments for {}\n{}", name, HELP));
+        let number_or = |i: usize, default: u16| match arguments.get(i) {
+            Some(text) => number(text),
+            None => Ok(default),
+        };
+
+        let pc = emulator.cpu().registers().pc();
+        let mut text = String::new();
+        let action = match name.as_str() {
+            "c" | "continue" => self.resume(emulator, Mode::Run),
+            "s" | "step" => {
+                let count = number_or(0, 1)?.max(1);
+                self.resume(emulator, Mode::Step(count as u32))
+            },
+            "o" | "over" => {
+                let mode = match over_length(emulator.machine(), pc) {
+                    Some(length) => Mode::Over {
+                        pc: pc.wrapping_add(length),
+                        sp: emulator.cpu().registers().get16(Reg16::SP),
+                    },
+                    None => Mode::Step(1),
+                };
+                self.resume(emulator, mode)
+            },
+            "out" => {
+                let sp = emulator.cpu().registers().get16(Reg16::SP);
+                self.resume(emulator, Mode::Out { sp })
+            },
+            "r" | "regs" => {
+                if !arguments.is_empty() {
+                    let trace = emulator.trace_cpu();
+                    set_register(emulator.cpu(), trace, argument(0)?, number(argument(1)?)?)?;
+                }
+                text = registers(emulator);
+                Action::Prompt
+            },
+            "m" | "mem" => {
+                text = dump(emulator.machine(), number(argument(0)?)?, number_or(1, DUMP_BYTES)?);
+                Action::Prompt
+            },
+            "e" | "edit" => {
+                let address = number(argument(0)?)?;
+                let bytes = arguments[1..].iter().map(|text| byte(text))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                if bytes.is_empty() {
+                    return Err(format!("Missing bytes to write\n{}", HELP));
+                }
+                let machine = emulator.machine();
+                for (i, &value) in bytes.iter().enumerate() {
+                    machine.poke(address.wrapping_add(i as u16), value);
+                }
+                text = dump(machine, address, bytes.len() as u16);
+                Action::Prompt
+            },
+            "d" | "dis" => {
+                let mut address = number_or(0, pc)?;
+                let mut lines = Vec::new();
+                for _ in 0..number_or(1, DISASSEMBLY_LINES)? {
+                    let (line, length) = disassembly_line(emulator.machine(), address);
+                    lines.push(line);
+                    address = address.wrapping_add(length);
+                }
+                text = lines.join("\n");
+                Action::Prompt
+            },
+            "b" | "break" => {
+                text = self.add(emulator, Breakpoint::Pc(number(argument(0)?)?));
+                Action::Prompt
+            },
+            "w" | "watch" => {
+                let range = argument(0)?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (number(start)?, number(end)?),
+                    None => (number(range)?, number(range)?),
+                };
+                if start > end {
+                    return Err(format!("Invalid range {}", range));
+                }
+                let (read, write) = match arguments.get(1).map(|kind| kind.to_ascii_lowercase()).as_deref() {
+                    None | Some("w") => (false, true),
+                    Some("r") => (true, false),
+                    Some("rw") => (true, true),
+                    Some(kind) => return Err(format!("Invalid watchpoint kind {}, r, w or rw", kind)),
+                };
+                text = self.add(emulator, Breakpoint::Memory { start, end, read, write });
+                Action::Prompt
+            },
+            "p" | "port" => {
+                let port = byte(argument(0)?)?;
+                let (input, output) = match arguments.get(1).map(|kind| kind.to_ascii_lowercase()).as_deref() {
+                    None | Some("io") => (true, true),
+                    Some("in") => (true, false),
+                    Some("out") => (false, true),
+                    Some(kind) => return Err(format!("Invalid port breakpoint kind {}, in, out or io", kind)),
+                };
+                text = self.add(emulator, Breakpoint::Port { port, input, output });
+                Action::Prompt
+            },
+            "l" | "list" => {
+                text = if self.breakpoints.is_empty() {
+                    "No breakpoints".to_string()
+                } else {
+                    self.breakpoints.iter().enumerate()
+                        .map(|(i, breakpoint)| format!("{}: {}", i + 1, describe(breakpoint)))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                Action::Prompt
+            },
+            "del" | "delete" => {
+                match arguments.first() {
+                    Some(index) => {
+                        let index = number(index)? as usize;
+                        if index == 0 || index > self.breakpoints.len() {
+                            return Err(format!("No breakpoint {}", index));
+                        }
+                        let breakpoint = self.breakpoints.remove(index - 1);
+                        text = format!("Deleted {}", describe(&breakpoint));
+                    },
+                    None => {
+                        self.breakpoints.clear();
+                        text = "Deleted all the breakpoints".to_string();
+                    },
+                }
+                emulator.machine().set_monitor(self.is_monitoring());
+                Action::Prompt
+            },
+            "q" | "quit" => Action::Quit,
+            "h" | "help" | "?" => {
+                text = HELP.to_string();
+                Action::Prompt
+            },
+            _ => return Err(format!("Unknown command {}\n{}", name, HELP)),
+        };
+        Ok((action, text))
+    }
+
+    fn add(&mut self, emulator: &mut Emulator, breakpoint: Breakpoint) -> String {
+        self.breakpoints.push(breakpoint);
+        emulator.machine().set_monitor(self.is_monitoring());
+        format!("{}: {}", self.breakpoints.len(), describe(&breakpoint))
+    }
+
+    // The accesses are collected only for the watchpoints and the ports
+    fn is_monitoring(&self) -> bool {
+        self.breakpoints.iter().any(|breakpoint| !matches!(breakpoint, Breakpoint::Pc(_)))
+    }
+}
+
+fn describe(breakpoint: &Breakpoint) -> String {
+    match *breakpoint {
+        Breakpoint::Pc(address) => format!("break {:04X}", address),
+        Breakpoint::Memory { start, end, read, write } => format!("watch {:04X}-{:04X} {}{}",
+            start, end, if read {"r"} else {""}, if write {"w"} else {""}),
+        Breakpoint::Port { port, input, output } => format!("port {:02X} {}",
+            port, if input && output {"io"} else if input {"in"} else {"out"}),
+    }
+}
+
+fn number(text: &str) -> Result<u16, String> {
+    let digits = text.strip_prefix("0x").unwrap_or(text).trim_end_matches(['h', 'H']);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex number {}", text))
+}
+
+fn byte(text: &str) -> Result<u8, String> {
+    match number(text)? {
+        value if value <= 0xff => Ok(value as u8),
+        _ => Err(format!("Invalid byte {}", text)),
+    }
+}
+
+fn registers(emulator: &mut Emulator) -> String {
+    let interrupts = if emulator.interrupts_enabled() {"EI"} else {"DI"};
+    let trace = emulator.trace_cpu();
+    let cpu = emulator.cpu();
+    let registers = cpu.registers();
+    let f = registers.get8(Reg8::F);
+    let flags: String = "SZ5H3PNC".chars().enumerate()
+        .map(|(i, flag)| if f & (0x80 >> i) != 0 {flag} else {'-'})
+        .collect();
+    let main = format!("PC={:04X} SP={:04X} AF={:04X} BC={:04X} DE={:04X} HL={:04X} IX={:04X} IY={:04X} {}",
+        registers.pc(), registers.get16(Reg16::SP), registers.get16(Reg16::AF),
+        registers.get16(Reg16::BC), registers.get16(Reg16::DE), registers.get16(Reg16::HL),
+        registers.get16(Reg16::IX), registers.get16(Reg16::IY), flags);
+    let i = registers.get8(Reg8::I);
+    let r = registers.get8(Reg8::R);
+
+    exchange(cpu, trace);
+    let registers = cpu.registers();
+    let alternate = format!("AF'={:04X} BC'={:04X} DE'={:04X} HL'={:04X} I={:02X} R={:02X} {}",
+        registers.get16(Reg16::AF), registers.get16(Reg16::BC), registers.get16(Reg16::DE),
+        registers.get16(Reg16::HL), i, r, interrupts);
+    exchange(cpu, trace);
+    format!("{}\n{}", main, alternate)
+}
+
+fn set_register(cpu: &mut Cpu, trace: bool, name: &str, value: u16) -> Result<(), String> {
+    let name = name.to_ascii_uppercase();
+    let reg8 = match name.as_str() {
+        "A" => Some(Reg8::A), "F" => Some(Reg8::F), "B" => Some(Reg8::B), "C" => Some(Reg8::C),
+        "D" => Some(Reg8::D), "E" => Some(Reg8::E), "H" => Some(Reg8::H), "L" => Some(Reg8::L),
+        "I" => Some(Reg8::I), "R" => Some(Reg8::R),
+        _ => None,
+    };
+    if let Some(reg8) = reg8 {
+        if value > 0xff {
+            return Err(format!("Invalid value {:X} for {}", value, name));
+        }
+        cpu.registers().set8(reg8, value as u8);
+        return Ok(());
+    }
+
+    let (register, alternate) = match name.strip_suffix('\'') {
+        Some(name) => (name, true),
+        None => (name.as_str(), false),
+    };
+    let reg16 = match register {
+        "AF" => Reg16::AF, "BC" => Reg16::BC, "DE" => Reg16::DE, "HL" => Reg16::HL,
+        "IX" if !alternate => Reg16::IX,
+        "IY" if !alternate => Reg16::IY,
+        "SP" if !alternate => Reg16::SP,
+        "PC" if !alternate => {
+            cpu.registers().set_pc(value);
+            return Ok(());
+        },
+        _ => return Err(format!("Unknown register {}", name)),
+    };
+    if alternate {
+        exchange(cpu, trace);
+        cpu.registers().set16(reg16, value);
+        exchange(cpu, trace);
+    } else {
+        cpu.registers().set16(reg16, value);
+    }
+    Ok(())
+}
+
+fn dump(machine: &mut MczMachine, address: u16, count: u16) -> String {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < count {
+        let start = address.wrapping_add(offset);
+        let bytes: Vec<u8> = (0..(count - offset).min(16))
+            .map(|i| machine.peek(start.wrapping_add(i)))
+            .collect();
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let ascii: String = bytes.iter()
+            .map(|&byte| if (0x20..0x7f).contains(&byte) {byte as char} else {'.'})
+            .collect();
+        lines.push(format!("{:04X}  {:47}  {}", start, hex.join(" "), ascii));
+        offset += bytes.len() as u16;
+    }
+    lines.join("\n")
+}
+
+/// Address, bytes and disassembly of the instruction, and its length.
+fn disassembly_line(machine: &mut MczMachine, address: u16) -> (String, u16) {
+    // A new CPU to decode, the state of the prefixes is not kept
+    let mut cpu = Cpu::new_z80();
+    cpu.registers().set_pc(address);
+    let mut text = cpu.disasm_instruction(machine);
+    // iz80 shows HL for the instructions on IX and IY other than (IX+d)
+    let index = match machine.peek(address) {
+        0xdd => "IX",
+        0xfd => "IY",
+        _ => "HL",
+    };
+    if !text.contains(index) {
+        text = text.replace("HL", index);
+    }
+    let length = instruction_length(machine, address);
+    let bytes: Vec<String> = (0..length)
+        .map(|i| format!("{:02X}", machine.peek(address.wrapping_add(i))))
+        .collect();
+    (format!("{:04X}  {:12} {}", address, bytes.join(" "), text), length)
+}
+
+/// Length in bytes of the instruction.
+fn instruction_length(machine: &MczMachine, address: u16) -> u16 {
+    let mut prefixes = 0;
+    let mut opcode = machine.peek(address);
+    while opcode == 0xdd || opcode == 0xfd {
+        prefixes += 1;
+        opcode = machine.peek(address.wrapping_add(prefixes));
+    }
+    let indexed = prefixes > 0;
+    let length = match opcode {
+        0xcb => if indexed {3} else {2},
+        0xed => match machine.peek(address.wrapping_add(prefixes + 1)) {
+            // LD (nn),rr and LD rr,(nn)
+            0x43 | 0x4b | 0x53 | 0x5b | 0x63 | 0x6b | 0x73 | 0x7b => 4,
+            _ => 2,
+        },
+        _ => {
+            let length = match opcode {
+                0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a
+                    | 0xc2 | 0xc3 | 0xc4 | 0xca | 0xcc | 0xcd | 0xd2 | 0xd4 | 0xda | 0xdc
+                    | 0xe2 | 0xe4 | 0xea | 0xec | 0xf2 | 0xf4 | 0xfa | 0xfc => 3,
+                0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e
+                    | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38
+                    | 0xc6 | 0xce | 0xd3 | 0xd6 | 0xdb | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => 2,
+                _ => 1,
+            };
+            // With a prefix, (HL) is (IX+d) with a displacement byte
+            let displacement = indexed && (matches!(opcode, 0x34..=0x36)
+                || (0x40..=0x7f).contains(&opcode) && opcode != 0x76
+                    && (opcode & 0x07 == 0x06 || opcode & 0xf8 == 0x70)
+                || (0x80..=0xbf).contains(&opcode) && opcode & 0x07 == 0x06);
+            length + displacement as u16
+        },
+    };
+    prefixes + length
+}
+
+/// Length of the instruction if it can be stepped over: the CALL and RST
+/// that return to the next instruction and the repeating block instructions.
+fn over_length(machine: &MczMachine, address: u16) -> Option<u16> {
+    match machine.peek(address) {
+        0xcd | 0xc4 | 0xcc | 0xd4 | 0xdc | 0xe4 | 0xec | 0xf4 | 0xfc => Some(3),
+        0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => Some(1),
+        0xed => match machine.peek(address.wrapping_add(1)) {
+            0xb0..=0xb3 | 0xb8..=0xbb => Some(2),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns true for RET, the conditional RET, RETI and RETN.
+fn is_return(machine: &MczMachine, address: u16) -> bool {
+    match machine.peek(address) {
+        0xc9 | 0xc0 | 0xc8 | 0xd0 | 0xd8 | 0xe0 | 0xe8 | 0xf0 | 0xf8 => true,
+        0xed => matches!(machine.peek(address.wrapping_add(1)), 0x45 | 0x4d),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::MemorySerial;
+
+    // LD HL,5000h; CALL 4010h; LD A,(HL); NOP; JR $
+    // 4010h: LD (HL),42h; RET
+    fn new_emulator() -> Emulator {
+        let mut emulator = Emulator::new(Vec::new(), Box::new(MemorySerial::new()));
+        let machine = emulator.machine();
+        for (i, &byte) in [0x21, 0x00, 0x50, 0xcd, 0x10, 0x40, 0x7e, 0x00, 0x18, 0xfe].iter().enumerate() {
+            machine.poke(0x4000 + i as u16, byte);
+        }
+        for (i, &byte) in [0x36, 0x42, 0xc9].iter().enumerate() {
+            machine.poke(0x4010 + i as u16, byte);
+        }
+        let registers = emulator.cpu().registers();
+        registers.set_pc(0x4000);
+        registers.set16(Reg16::SP, 0x8000);
+        emulator
+    }
+
+    // Runs a command and the emulator until the debugger stops
+    fn run(debugger: &mut Debugger, emulator: &mut Emulator, command: &str) -> String {
+        assert!(debugger.command(emulator, command).0 == Action::Resume);
+        for _ in 0..1000 {
+            emulator.step();
+            if debugger.check(emulator) {
+                return debugger.status(emulator);
+            }
+        }
+        panic!("The debugger didn't stop");
+    }
+
+    fn pc(emulator: &mut Emulator) -> u16 {
+        emulator.cpu().registers().pc()
+    }
+
+    fn length(bytes: &[u8]) -> u16 {
+        let mut emulator = new_emulator();
+        for (i, &byte) in bytes.iter().enumerate() {
+            emulator.machine().poke(0x6000 + i as u16, byte);
+        }
+        instruction_length(emulator.machine(), 0x6000)
+    }
+
+    #[test]
+    fn instruction_lengths() {
+        assert_eq!(length(&[0x00]), 1);
+        assert_eq!(length(&[0x3e]), 2);
+        assert_eq!(length(&[0x21]), 3);
+        assert_eq!(length(&[0xcb, 0x7e]), 2);
+        assert_eq!(length(&[0xed, 0xb0]), 2);
+        assert_eq!(length(&[0xed, 0x4b]), 4);
+        assert_eq!(length(&[0xdd, 0x21]), 4);
+        assert_eq!(length(&[0xdd, 0x7e]), 3);
+        assert_eq!(length(&[0xfd, 0x36]), 4);
+        assert_eq!(length(&[0xdd, 0xcb]), 4);
+        assert_eq!(length(&[0xdd, 0xe9]), 2);
+    }
+
+    #[test]
+    fn over_lengths() {
+        let mut emulator = new_emulator();
+        let machine = emulator.machine();
+        assert_eq!(over_length(machine, 0x4003), Some(3));
+        assert_eq!(over_length(machine, 0x4000), None);
+        machine.poke(0x6000, 0xff);
+        assert_eq!(over_length(machine, 0x6000), Some(1));
+        machine.poke(0x6000, 0xed);
+        machine.poke(0x6001, 0xb0);
+        assert_eq!(over_length(machine, 0x6000), Some(2));
+        assert!(is_return(machine, 0x4012));
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let mut emulator = new_emulator();
+        let mut debugger = Debugger::new();
+        run(&mut debugger, &mut emulator, "s");
+        assert_eq!(pc(&mut emulator), 0x4003);
+        run(&mut debugger, &mut emulator, "o");
+        assert_eq!(pc(&mut emulator), 0x4006);
+        assert_eq!(emulator.machine().peek(0x5000), 0x42);
+
+        let mut emulator = new_emulator();
+        run(&mut debugger, &mut emulator, "s 2");
+        assert_eq!(pc(&mut emulator), 0x4010);
+        let status = run(&mut debugger, &mut emulator, "out");
+        assert!(status.starts_with("Returned to 4006"), "{}", status);
+        assert_eq!(pc(&mut emulator), 0x4006);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut emulator = new_emulator();
+        let mut debugger = Debugger::new();
+        debugger.command(&mut emulator, "b 4010");
+        let status = run(&mut debugger, &mut emulator, "c");
+        assert!(status.starts_with("Breakpoint 1 at 4010"), "{}", status);
+
+        debugger.command(&mut emulator, "del");
+        debugger.command(&mut emulator, "w 5000");
+        let status = run(&mut debugger, &mut emulator, "c");
+        assert!(status.starts_with("Watchpoint 1: write 42 to 5000 at 4010"), "{}", status);
+        assert_eq!(pc(&mut emulator), 0x4012);
+
+        // The fetch of LD A,(HL) is not a read of the range
+        debugger.command(&mut emulator, "del 1");
+        debugger.command(&mut emulator, "w 4000-5000 r");
+        let status = run(&mut debugger, &mut emulator, "c");
+        assert!(status.starts_with("Watchpoint 1: read 42 from 5000 at 4006"), "{}", status);
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let mut emulator = new_emulator();
+        let mut debugger = Debugger::new();
+        let (action, text) = debugger.command(&mut emulator, "w 5000-4000");
+        assert!(action == Action::Prompt);
+        assert_eq!(text, "Invalid range 5000-4000");
+        assert_eq!(debugger.command(&mut emulator, "del 1").1, "No breakpoint 1");
+        assert!(debugger.command(&mut emulator, "x").1.starts_with("Unknown command x"));
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::Result;
+
+use iz80::*;
+
+use super::floppy::{floppy_busy, rom_floopy};
+use super::interrupts::Interrupts;
+use super::mcz_machine::*;
+use super::media::Disk;
+use super::pio::ParallelDevice;
+use super::serial::SerialDevice;
+use super::snapshot::{self, Reader, Writer};
+use super::timing::instruction_cycles;
+
+/// T-states per second of the 2.5 MHz clock
+pub const CLOCK_HZ: u64 = 2_500_000;
+
+// Instructions between checks of the break key
+const BREAK_POLL_INSTRUCTIONS: u32 = 10000;
+
+// T-states of a HALT cycle
+const HALT_CYCLES: u32 = 4;
+
+/// The MCZ-1 computer, without a host user interface.
+pub struct Emulator {
+    cpu: Cpu,
+    machine: MczMachine,
+    interrupts: Interrupts,
+    floppy_trap: bool,
+    trace_cpu: bool,
+    trace_floppy: bool,
+    break_poll: u32,
+    cycles: u64,
+}
+
+impl Emulator {
+    pub fn new(drives: Vec<Box<dyn Disk>>, console: Box<dyn SerialDevice>) -> Emulator {
+        Emulator {
+            cpu: Cpu::new_z80(),
+            machine: MczMachine::new(drives, console),
+            interrupts: Interrupts::new(),
+            floppy_trap: true,
+            trace_cpu: false,
+            trace_floppy: false,
+            break_poll: 0,
+            cycles: 0,
+        }
+    }
+
+    /// Traps the calls to the PROM floppy driver instead of emulating the
+    /// floppy controller ports. Enabled by default.
+    pub fn set_floppy_trap(&mut self, floppy_trap: bool) {
+        self.floppy_trap = floppy_trap;
+    }
+
+    pub fn set_trace_cpu(&mut self, trace: bool) {
+        self.trace_cpu = trace;
+        self.cpu.set_trace(trace);
+    }
+
+    pub fn trace_cpu(&self) -> bool {
+        self.trace_cpu
+    }
+
+    pub fn set_trace_io(&mut self, trace: bool) {
+        self.machine.set_trace_io(trace);
+    }
+
+    pub fn set_trace_floppy(&mut self, trace: bool) {
+        self.trace_floppy = trace;
+        self.machine.mdc().set_trace(trace);
+    }
+
+    pub fn set_parallel(&mut self, device: Option<Box<dyn ParallelDevice>>) {
+        self.machine.set_parallel(device);
+    }
+
+    /// Inserts a disk on an empty drive. Returns the disk back if the
+    /// drive is not empty or doesn't exist.
+    pub fn insert_disk(&mut self, drive: usize, disk: Box<dyn Disk>) -> std::result::Result<(), Box<dyn Disk>> {
+        self.machine.mdc().insert(drive, disk)
+    }
+
+    /// Removes the disk of a drive, None if the drive was empty.
+    pub fn eject_disk(&mut self, drive: usize) -> Option<Box<dyn Disk>> {
+        self.machine.mdc().eject(drive)
+    }
+
+    pub fn cpu(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn machine(&mut self) -> &mut MczMachine {
+        &mut self.machine
+    }
+
+    /// T-states elapsed since the start.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Returns true if the CPU is halted with the interrupts disabled, only
+    /// the BREAK key can continue the execution.
+    pub fn is_stopped(&self) -> bool {
+        self.interrupts.is_halted() && !self.interrupts.is_enabled()
+    }
+
+    /// Returns true if the maskable interrupts are enabled.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts.is_enabled()
+    }
+
+    pub(crate) fn save_state(&mut self, writer: &mut Writer) {
+        snapshot::save_registers(&mut self.cpu, self.trace_cpu, writer);
+        self.interrupts.save_state(writer);
+        writer.u64(self.cycles);
+        writer.u32(self.break_poll);
+        self.machine.save_state(writer);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        snapshot::load_registers(&mut self.cpu, self.trace_cpu, reader)?;
+        self.interrupts.load_state(reader)?;
+        self.cycles = reader.u64()?;
+        self.break_poll = reader.u32()?;
+        self.machine.load_state(reader)
+    }
+
+    /// Executes an instruction, or waits a HALT cycle. Returns the T-states
+    /// elapsed.
+    pub fn step(&mut self) -> u32 {
+        let cpu = &mut self.cpu;
+        let machine = &mut self.machine;
+
+        self.break_poll += 1;
+        if self.break_poll == BREAK_POLL_INSTRUCTIONS {
+            self.break_poll = 0;
+            if machine.break_pressed() {
+                self.interrupts.nmi(cpu, machine);
+            }
+        }
+
+        self.interrupts.accept(cpu, machine);
+
+        let pc = cpu.registers().pc();
+        if self.floppy_trap && pc == FLOPPY_HANDLER && !floppy_busy(machine) {
+            // FLOPPY REQUEST, if busy the PROM waits for the previous async request
+            let iy = cpu.registers().get16(Reg16::IY);
+            rom_floopy(machine, iy, self.trace_floppy);
+            cpu.registers().set_pc(0x0797); // Jump to the RET
+        }
+
+        let cycles = if self.interrupts.is_halted() {
+            HALT_CYCLES
+        } else {
+            let cycles = instruction_cycles(machine, cpu.registers().pc());
+            if self.interrupts.decode(cpu, machine) {
+                cpu.execute_instruction(machine);
+                self.interrupts.executed(cpu);
+            }
+            cycles
+        };
+
+        machine.advance(cycles);
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    /// Runs for at least the T-states given. Returns early if the CPU stops.
+    pub fn run_for(&mut self, cycles: u64) {
+        let end = self.cycles + cycles;
+        while self.cycles < end && !self.is_stopped() {
+            self.step();
+        }
+    }
+
+    /// Runs until the predicate, checked after every step, returns true.
+    pub fn run_until<F>(&mut self, mut predicate: F)
+        where F: FnMut(&mut Emulator) -> bool {
+        loop {
+            self.step();
+            if predicate(self) {
+                break;
+            }
+        }
+    }
+}
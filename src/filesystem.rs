@@ -0,0 +1,842 @@
+use std::io::{Error, ErrorKind, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::media::*;
+
+/*
+RIO filesystem, read from the sectors of a disk.
+
+The sector trailers link the sectors of a file: the back and forward
+pointers have the sector and the track, 0xff 0xff is the end of the chain.
+The records of the files are stored in blocks of consecutive sectors, all
+the sectors of a block have the same pointers to the first sector of the
+previous and next blocks.
+
+Fixed locations:
+ - Track 22 sector 0: descriptor of the directory.
+ - Track 23 sectors 0 to 2: disk label on the first 24 bytes and the
+   allocation map on the next 308 bytes, a bit per sector in track order,
+   most significant bit first. 1 for allocated. At 375 and 380 the counts
+   of used and available sectors, 16 bits, checked by STATUS.
+
+The directory is a file with the entries packed on each sector and 0xff
+after the last one. Each entry has:
+ - 1 byte for the length of the name, bit 7 set if the file is secret
+ - The name
+ - 2 bytes for the sector and track of the file descriptor
+
+The descriptor is the first sector of a file:
+ - 0x06: sector and track of the directory sector with the entry
+ - 0x08: sector and track of the first data block
+ - 0x0a: sector and track of the last data block
+ - 0x0c: type, 0x80 procedure, 0x40 directory, 0x20 ASCII, 0x10 binary
+ - 0x0d: record count, 16 bits
+ - 0x0f: record length, 16 bits
+ - 0x13: properties, 0x80 write protected, 0x40 erase protected, 0x20
+   locked, 0x10 secret
+ - 0x14: starting address of the procedures, 16 bits
+ - 0x16: bytes used on the last record, 16 bits
+ - 0x18: creation date, YYMMDD in ASCII followed by 2 zeros
+ - 0x20: last modification date, the same format
+
+The trailer of the descriptor points back to the directory sector with the
+entry and forward to the first data block.
+*/
+
+pub const DIRECTORY_TRACK: usize = 22;
+pub const MAP_TRACK: usize = 23;
+pub const LABEL_SIZE: usize = 24;
+pub const MAP_SECTORS: usize = 3;
+const MAP_SIZE: usize = TRACK_COUNT * SECTOR_COUNT / 8;
+const USED_OFFSET: usize = 375;
+const AVAILABLE_OFFSET: usize = 380;
+// Written by RIO 2.2 before the available count, other versions use 'K'
+const MAP_MARK_OFFSET: usize = 379;
+const MAP_MARK: u8 = b'J';
+const MAX_NAME: usize = 32;
+
+// Directory of a new filesystem, with the interleave of the RIO disks
+const DIRECTORY_NAME: &str = "DIRECTORY";
+const DIRECTORY_SECTORS: [usize; 10] = [5, 10, 15, 20, 25, 1, 6, 11, 16, 21];
+
+const END_OF_CHAIN: u8 = 0xff;
+const END_OF_DIRECTORY: u8 = 0xff;
+const SECRET: u8 = 0x80;
+
+// Offset of the forward pointer after the sector data
+const FORWARD_POINTER: usize = SECTOR_SIZE + 2;
+
+pub const TYPE_PROCEDURE: u8 = 0x80;
+pub const TYPE_DIRECTORY: u8 = 0x40;
+pub const TYPE_ASCII: u8 = 0x20;
+pub const TYPE_BINARY: u8 = 0x10;
+
+pub const PROP_WRITE_PROTECTED: u8 = 0x80;
+pub const PROP_ERASE_PROTECTED: u8 = 0x40;
+pub const PROP_LOCKED: u8 = 0x20;
+pub const PROP_SECRET: u8 = 0x10;
+
+/// Location of a sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub track: usize,
+    pub sector: usize,
+}
+
+impl Address {
+    pub fn new(track: usize, sector: usize) -> Address {
+        Address { track, sector }
+    }
+
+    /// Pointer as stored on the disk, sector first. None for the end
+    /// of the chain.
+    pub fn from_pointer(data: &[u8]) -> Option<Address> {
+        if data[1] == END_OF_CHAIN {
+            None
+        } else {
+            Some(Address::new(data[1] as usize, data[0] as usize))
+        }
+    }
+
+    fn to_pointer(address: Option<Address>) -> [u8; 2] {
+        match address {
+            Some(address) => [address.sector as u8, address.track as u8],
+            None => [END_OF_CHAIN, END_OF_CHAIN],
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.track < TRACK_COUNT && self.sector < SECTOR_COUNT
+    }
+}
+
+/// File of the directory, with the information of the descriptor.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub name: String,
+    pub descriptor: Address,
+    /// Sector of the directory with the entry
+    pub directory_sector: Option<Address>,
+    pub first_block: Option<Address>,
+    pub last_block: Option<Address>,
+    pub file_type: u8,
+    pub record_count: usize,
+    pub record_length: usize,
+    pub properties: u8,
+    pub starting_address: u16,
+    pub last_record_bytes: usize,
+    pub created: Option<String>,
+    pub modified: Option<String>,
+}
+
+impl FileEntry {
+    /// Type as shown by CAT, like "A" or "P1".
+    pub fn type_name(&self) -> String {
+        let letter = match self.file_type & 0xf0 {
+            TYPE_PROCEDURE => "P",
+            TYPE_DIRECTORY => "D",
+            TYPE_ASCII => "A",
+            TYPE_BINARY => "B",
+            _ => "?",
+        };
+        match self.file_type & 0x0f {
+            0 => letter.to_string(),
+            subtype => format!("{}{}", letter, subtype),
+        }
+    }
+
+    /// Properties as shown by CAT, like "WE".
+    pub fn properties_name(&self) -> String {
+        [(PROP_WRITE_PROTECTED, 'W'), (PROP_ERASE_PROTECTED, 'E'),
+            (PROP_LOCKED, 'L'), (PROP_SECRET, 'S')].iter()
+            .filter(|(mask, _)| self.properties & mask != 0)
+            .map(|(_, letter)| letter)
+            .collect()
+    }
+
+    pub fn is_ascii(&self) -> bool {
+        self.file_type & 0xf0 == TYPE_ASCII
+    }
+
+    pub fn is_secret(&self) -> bool {
+        self.properties & PROP_SECRET != 0
+    }
+
+    /// Sectors of each record, the size of the blocks.
+    pub fn block_sectors(&self) -> usize {
+        self.record_length.div_ceil(SECTOR_SIZE).max(1)
+    }
+
+    /// Sectors used, the descriptor included.
+    pub fn sectors(&self) -> usize {
+        self.record_count * self.block_sectors() + 1
+    }
+
+    /// Size in bytes of the content.
+    pub fn size(&self) -> usize {
+        match self.record_count {
+            0 => 0,
+            count => (count - 1) * self.record_length + self.last_record_bytes,
+        }
+    }
+}
+
+/// RIO filesystem on a disk.
+pub struct Filesystem<D: Disk> {
+    disk: D,
+}
+
+impl<D: Disk> Filesystem<D> {
+    pub fn new(disk: D) -> Filesystem<D> {
+        Filesystem { disk }
+    }
+
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn disk_mut(&mut self) -> &mut D {
+        &mut self.disk
+    }
+
+    pub fn into_disk(self) -> D {
+        self.disk
+    }
+
+    /// Returns the 136 bytes of a sector without the two header bytes.
+    fn sector(&self, address: Address) -> Result<&[u8]> {
+        let data = self.disk.read_sector(address.track, address.sector);
+        if data.len() < SECTOR_SIZE_IN_FILE {
+            return Err(invalid(format!("Sector {} of track {} out of range",
+                address.sector, address.track)));
+        }
+        Ok(&data[2..])
+    }
+
+    /// Label of the disk, as shown by STATUS.
+    pub fn label(&self) -> Result<String> {
+        let data = self.sector(Address::new(MAP_TRACK, 0))?;
+        let label = data[..LABEL_SIZE].iter()
+            .take_while(|&&ch| ch != b'\r' && ch != 0 && ch != 0xff)
+            .map(|&ch| (ch & 0x7f) as char)
+            .collect::<String>();
+        Ok(label.trim_end().to_string())
+    }
+
+    /// Data of the sectors with the label and the allocation map.
+    fn map_sectors(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(MAP_SECTORS * SECTOR_SIZE);
+        for sector in 0..MAP_SECTORS {
+            data.extend_from_slice(&self.sector(Address::new(MAP_TRACK, sector))?[..SECTOR_SIZE]);
+        }
+        Ok(data)
+    }
+
+    /// Allocation map, a bit per sector.
+    pub fn allocation_map(&self) -> Result<Vec<u8>> {
+        Ok(self.map_sectors()?[LABEL_SIZE..LABEL_SIZE + MAP_SIZE].to_vec())
+    }
+
+    /// Writes the allocation map and the counts of used and available
+    /// sectors.
+    pub fn set_allocation_map(&mut self, map: &[u8]) -> Result<()> {
+        let mut data = self.map_sectors()?;
+        data[LABEL_SIZE..LABEL_SIZE + MAP_SIZE].copy_from_slice(map);
+        update_counts(&mut data);
+        self.write_map_sectors(&data)
+    }
+
+    /// Counts of used and available sectors stored with the map, as shown
+    /// by STATUS.
+    pub fn statistics(&self) -> Result<(usize, usize)> {
+        let data = self.map_sectors()?;
+        let word = |offset: usize| data[offset] as usize | (data[offset + 1] as usize) << 8;
+        Ok((word(USED_OFFSET), word(AVAILABLE_OFFSET)))
+    }
+
+    /// Number of sectors allocated on the map.
+    pub fn used_sectors(&self) -> Result<usize> {
+        Ok(self.allocation_map()?.iter().map(|b| b.count_ones() as usize).sum())
+    }
+
+    /// Sectors of the directory, the descriptor not included.
+    pub fn directory_sectors(&self) -> Result<Vec<Address>> {
+        let descriptor = self.sector(Address::new(DIRECTORY_TRACK, 0))?;
+        let mut sectors = Vec::new();
+        let mut next = Address::from_pointer(&descriptor[0x08..]);
+        while let Some(address) = next {
+            if !address.is_valid() || sectors.len() > TRACK_COUNT * SECTOR_COUNT {
+                return Err(invalid("Invalid directory chain".to_string()));
+            }
+            sectors.push(address);
+            next = Address::from_pointer(&self.sector(address)?[FORWARD_POINTER..]);
+        }
+        Ok(sectors)
+    }
+
+    /// Entries of the directory as name and descriptor address.
+    pub fn directory(&self) -> Result<Vec<(String, Address)>> {
+        let mut entries = Vec::new();
+        for address in self.directory_sectors()? {
+            entries.extend(self.directory_entries(address)?);
+        }
+        Ok(entries)
+    }
+
+    /// Entries of a sector of the directory.
+    pub fn directory_entries(&self, address: Address) -> Result<Vec<(String, Address)>> {
+        Ok(directory_entries(&self.sector(address)?[..SECTOR_SIZE]).0)
+    }
+
+    /// Reads the descriptor of a file.
+    pub fn file_entry(&self, name: &str, descriptor: Address) -> Result<FileEntry> {
+        if !descriptor.is_valid() {
+            return Err(invalid(format!("Invalid descriptor address for {}", name)));
+        }
+        let data = self.sector(descriptor)?;
+        let word = |offset: usize| data[offset] as usize | (data[offset + 1] as usize) << 8;
+        Ok(FileEntry {
+            name: name.to_string(),
+            descriptor,
+            directory_sector: Address::from_pointer(&data[0x06..]),
+            first_block: Address::from_pointer(&data[0x08..]),
+            last_block: Address::from_pointer(&data[0x0a..]),
+            file_type: data[0x0c],
+            record_count: word(0x0d),
+            record_length: word(0x0f),
+            properties: data[0x13],
+            starting_address: word(0x14) as u16,
+            last_record_bytes: word(0x16),
+            created: date(&data[0x18..0x1e]),
+            modified: date(&data[0x20..0x26]),
+        })
+    }
+
+    /// Files of the directory, the secret files included.
+    pub fn files(&self) -> Result<Vec<FileEntry>> {
+        self.directory()?.iter()
+            .map(|(name, descriptor)| self.file_entry(name, *descriptor))
+            .collect()
+    }
+
+    pub fn find(&self, name: &str) -> Result<FileEntry> {
+        self.files()?.into_iter()
+            .find(|file| file.name == name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("File {} not found", name)))
+    }
+
+    /// Addresses of the first sector of each data block, following the
+    /// forward pointers.
+    pub fn blocks(&self, file: &FileEntry) -> Result<Vec<Address>> {
+        let block_sectors = file.block_sectors();
+        let mut blocks = Vec::new();
+        let mut next = file.first_block;
+        while let Some(address) = next {
+            if !address.is_valid() || address.sector + block_sectors > SECTOR_COUNT {
+                return Err(invalid(format!("Invalid block address in {}", file.name)));
+            }
+            if blocks.len() >= file.record_count {
+                return Err(invalid(format!("Chain of {} longer than {} records",
+                    file.name, file.record_count)));
+            }
+            blocks.push(address);
+            next = Address::from_pointer(&self.sector(address)?[FORWARD_POINTER..]);
+        }
+        if blocks.len() < file.record_count {
+            return Err(invalid(format!("Chain of {} ends after {} of {} records",
+                file.name, blocks.len(), file.record_count)));
+        }
+        Ok(blocks)
+    }
+
+    /// Reads the content of a file.
+    pub fn read_file(&self, file: &FileEntry) -> Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(file.sectors() * SECTOR_SIZE);
+        for block in self.blocks(file)? {
+            for i in 0..file.block_sectors() {
+                let address = Address::new(block.track, block.sector + i);
+                content.extend_from_slice(&self.sector(address)?[..SECTOR_SIZE]);
+            }
+        }
+        content.truncate(file.size());
+        Ok(content)
+    }
+
+    /// Back and forward pointers of a sector.
+    pub fn pointers(&self, address: Address) -> Result<(Option<Address>, Option<Address>)> {
+        let data = self.sector(address)?;
+        Ok((Address::from_pointer(&data[SECTOR_SIZE..]), Address::from_pointer(&data[FORWARD_POINTER..])))
+    }
+
+    /// Rewrites the pointers of a sector, the data is kept.
+    pub fn set_pointers(&mut self, address: Address, back: Option<Address>, forward: Option<Address>) -> Result<()> {
+        let data = self.sector(address)?[..SECTOR_SIZE].to_vec();
+        self.write_sector(address, &data, back, forward)
+    }
+
+    /// Rewrites the links of a descriptor from the entry: the directory
+    /// sector and the first and last blocks, also on the pointers.
+    pub fn set_links(&mut self, file: &FileEntry) -> Result<()> {
+        let mut data = self.sector(file.descriptor)?[..SECTOR_SIZE].to_vec();
+        data[0x06..0x08].copy_from_slice(&Address::to_pointer(file.directory_sector));
+        data[0x08..0x0a].copy_from_slice(&Address::to_pointer(file.first_block));
+        data[0x0a..0x0c].copy_from_slice(&Address::to_pointer(file.last_block));
+        self.write_sector(file.descriptor, &data, file.directory_sector, file.first_block)
+    }
+
+    /// Writes a sector with the header, the pointers and the CRC.
+    fn write_sector(&mut self, address: Address, data: &[u8],
+            back: Option<Address>, forward: Option<Address>) -> Result<()> {
+        let mut sector = [0; SECTOR_SIZE_IN_FILE];
+        sector[0] = 0x80 | address.sector as u8;
+        sector[1] = address.track as u8;
+        sector[2..2 + SECTOR_SIZE].copy_from_slice(data);
+        sector[2 + SECTOR_SIZE..4 + SECTOR_SIZE].copy_from_slice(&Address::to_pointer(back));
+        sector[2 + FORWARD_POINTER..2 + FORWARD_POINTER + 2].copy_from_slice(&Address::to_pointer(forward));
+        set_sector_crc(&mut sector);
+        self.disk.write_sector(address.track, address.sector, &sector)
+    }
+
+    /// Writes the data of a sector, the back pointer is kept.
+    fn update_sector(&mut self, address: Address, data: &[u8], forward: Option<Address>) -> Result<()> {
+        let back = Address::from_pointer(&self.sector(address)?[SECTOR_SIZE..]);
+        self.write_sector(address, data, back, forward)
+    }
+
+    /// Sectors free on the allocation map. The fixed locations are never
+    /// free.
+    pub fn free_sectors(&self) -> Result<Vec<Address>> {
+        let map = self.allocation_map()?;
+        Ok((0..TRACK_COUNT * SECTOR_COUNT)
+            .filter(|i| map[i / 8] & (0x80 >> (i % 8)) == 0)
+            .map(|i| Address::new(i / SECTOR_COUNT, i % SECTOR_COUNT))
+            .filter(|address| *address != Address::new(DIRECTORY_TRACK, 0)
+                && !(address.track == MAP_TRACK && address.sector < MAP_SECTORS))
+            .collect())
+    }
+
+    /// Marks the sectors as allocated on the map.
+    fn allocate(&mut self, addresses: &[Address]) -> Result<()> {
+        let mut data = self.map_sectors()?;
+        set_allocated(&mut data, addresses);
+        self.write_map_sectors(&data)
+    }
+
+    fn write_map_sectors(&mut self, data: &[u8]) -> Result<()> {
+        for sector in 0..MAP_SECTORS {
+            let address = Address::new(MAP_TRACK, sector);
+            let forward = Address::from_pointer(&self.sector(address)?[FORWARD_POINTER..]);
+            self.update_sector(address, &data[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE], forward)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an empty RIO filesystem: the label, the allocation map and
+    /// the directory, that has itself as a secret entry.
+    pub fn initialize(&mut self, label: &str) -> Result<()> {
+        if label.len() > LABEL_SIZE || !label.bytes().all(|ch| ch == b' ' || ch.is_ascii_graphic()) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid label {}", label)));
+        }
+
+        let descriptor = Address::new(DIRECTORY_TRACK, 0);
+        let sectors: Vec<Address> = DIRECTORY_SECTORS.iter()
+            .map(|&sector| Address::new(DIRECTORY_TRACK, sector))
+            .collect();
+
+        for (i, &address) in sectors.iter().enumerate() {
+            let mut data = [0; SECTOR_SIZE];
+            let mut end = 0;
+            if i == 0 {
+                data[0] = SECRET | DIRECTORY_NAME.len() as u8;
+                data[1..1 + DIRECTORY_NAME.len()].copy_from_slice(DIRECTORY_NAME.as_bytes());
+                end = DIRECTORY_NAME.len() + 1;
+                data[end..end + 2].copy_from_slice(&Address::to_pointer(Some(descriptor)));
+                end += 2;
+            }
+            data[end] = END_OF_DIRECTORY;
+            let back = if i == 0 {descriptor} else {sectors[i - 1]};
+            self.write_sector(address, &data, Some(back), sectors.get(i + 1).copied())?;
+        }
+
+        let data = descriptor_data(sectors[0], &sectors, TYPE_DIRECTORY,
+            PROP_WRITE_PROTECTED | PROP_ERASE_PROTECTED | PROP_LOCKED | PROP_SECRET, SECTOR_SIZE);
+        self.write_sector(descriptor, &data, Some(sectors[0]), Some(sectors[0]))?;
+
+        // The label is padded with carriage returns
+        let mut data = vec![0; MAP_SECTORS * SECTOR_SIZE];
+        data[..LABEL_SIZE].fill(b'\r');
+        data[..label.len()].copy_from_slice(label.as_bytes());
+        data[MAP_MARK_OFFSET] = MAP_MARK;
+        let mut allocated = sectors;
+        allocated.push(descriptor);
+        allocated.extend((0..MAP_SECTORS).map(|sector| Address::new(MAP_TRACK, sector)));
+        set_allocated(&mut data, &allocated);
+        self.write_map_sectors(&data)
+    }
+
+    /// Writes a new file with records of 128 bytes, dated today.
+    pub fn write_file(&mut self, name: &str, file_type: u8, content: &[u8]) -> Result<FileEntry> {
+        if !is_valid_name(name) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid file name {}", name)));
+        }
+        if self.disk.is_write_protected() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Disk is write protected"));
+        }
+        if self.directory()?.iter().any(|(entry, _)| entry == name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("File {} already exists", name)));
+        }
+
+        // The entry goes on the first directory sector with room, or on a
+        // new sector at the end of the directory.
+        let entry_size = name.len() + 3;
+        let directory = self.directory_sectors()?;
+        let mut entry_location = None;
+        for &address in directory.iter() {
+            let end = directory_entries(&self.sector(address)?[..SECTOR_SIZE]).1;
+            if end + entry_size <= SECTOR_SIZE {
+                entry_location = Some((address, end));
+                break;
+            }
+        }
+
+        let mut free = self.free_sectors()?;
+        let new_directory_sector = match entry_location {
+            Some(_) => None,
+            None if free.is_empty() => return Err(Error::other("Disk full")),
+            None => {
+                let i = free.iter().position(|address| address.track == DIRECTORY_TRACK).unwrap_or(0);
+                Some(free.remove(i))
+            },
+        };
+        // The directory track is left for the directory while there is room
+        free.sort_by_key(|address| address.track == DIRECTORY_TRACK);
+        let record_count = content.len().div_ceil(SECTOR_SIZE);
+        if free.len() < record_count + 1 {
+            return Err(Error::other("Disk full"));
+        }
+        let descriptor = free[0];
+        let blocks = free[1..record_count + 1].to_vec();
+        let directory_sector = match entry_location {
+            Some((address, _)) => address,
+            None => new_directory_sector.unwrap(),
+        };
+
+        // RIO fills the end of the last record with 0xff, the console
+        // stops there when copying text.
+        for (i, &block) in blocks.iter().enumerate() {
+            let mut data = [0xff; SECTOR_SIZE];
+            let chunk = &content[i * SECTOR_SIZE..content.len().min((i + 1) * SECTOR_SIZE)];
+            data[..chunk.len()].copy_from_slice(chunk);
+            let back = if i == 0 {descriptor} else {blocks[i - 1]};
+            self.write_sector(block, &data, Some(back), blocks.get(i + 1).copied())?;
+        }
+
+        let last_record_bytes = content.len() - record_count.saturating_sub(1) * SECTOR_SIZE;
+        let data = descriptor_data(directory_sector, &blocks, file_type, 0, last_record_bytes);
+        self.write_sector(descriptor, &data, Some(directory_sector), blocks.first().copied())?;
+
+        let mut allocated = blocks.clone();
+        allocated.push(descriptor);
+        allocated.extend(new_directory_sector);
+        self.allocate(&allocated)?;
+
+        let mut entry = vec![name.len() as u8];
+        entry.extend_from_slice(name.as_bytes());
+        entry.extend_from_slice(&Address::to_pointer(Some(descriptor)));
+        match entry_location {
+            Some((address, end)) => {
+                let sector = self.sector(address)?;
+                let forward = Address::from_pointer(&sector[FORWARD_POINTER..]);
+                let mut data = sector[..SECTOR_SIZE].to_vec();
+                data[end..end + entry_size].copy_from_slice(&entry);
+                if end + entry_size < SECTOR_SIZE {
+                    data[end + entry_size] = END_OF_DIRECTORY;
+                }
+                self.update_sector(address, &data, forward)?;
+            },
+            None => self.append_directory_sector(&directory, directory_sector, &entry)?,
+        }
+
+        self.file_entry(name, descriptor)
+    }
+
+    /// Adds a sector with the entry at the end of the directory chain.
+    fn append_directory_sector(&mut self, directory: &[Address], address: Address, entry: &[u8]) -> Result<()> {
+        let descriptor = Address::new(DIRECTORY_TRACK, 0);
+        let mut data = [END_OF_DIRECTORY; SECTOR_SIZE];
+        data[..entry.len()].copy_from_slice(entry);
+        let back = directory.last().copied().unwrap_or(descriptor);
+        self.write_sector(address, &data, Some(back), None)?;
+
+        if let Some(&last) = directory.last() {
+            let data = self.sector(last)?[..SECTOR_SIZE].to_vec();
+            self.update_sector(last, &data, Some(address))?;
+        }
+
+        let mut data = self.sector(descriptor)?[..SECTOR_SIZE].to_vec();
+        if directory.is_empty() {
+            data[0x08..0x0a].copy_from_slice(&Address::to_pointer(Some(address)));
+        }
+        data[0x0a..0x0c].copy_from_slice(&Address::to_pointer(Some(address)));
+        let count = data[0x0d] as u16 | (data[0x0e] as u16) << 8;
+        data[0x0d..0x0f].copy_from_slice(&(count + 1).to_le_bytes());
+        let first = Address::from_pointer(&data[0x08..]);
+        self.update_sector(descriptor, &data, first)
+    }
+}
+
+/// Data of the descriptor of a file with records of 128 bytes, dated
+/// today.
+fn descriptor_data(directory_sector: Address, blocks: &[Address], file_type: u8,
+        properties: u8, last_record_bytes: usize) -> [u8; SECTOR_SIZE] {
+    let date = today();
+    let mut data = [0; SECTOR_SIZE];
+    data[0x06..0x08].copy_from_slice(&Address::to_pointer(Some(directory_sector)));
+    data[0x08..0x0a].copy_from_slice(&Address::to_pointer(blocks.first().copied()));
+    data[0x0a..0x0c].copy_from_slice(&Address::to_pointer(blocks.last().copied()));
+    data[0x0c] = file_type;
+    data[0x0d..0x0f].copy_from_slice(&(blocks.len() as u16).to_le_bytes());
+    data[0x0f..0x11].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    data[0x11..0x13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    data[0x13] = properties;
+    data[0x16..0x18].copy_from_slice(&(last_record_bytes as u16).to_le_bytes());
+    data[0x18..0x1e].copy_from_slice(date.as_bytes());
+    data[0x20..0x26].copy_from_slice(date.as_bytes());
+    data
+}
+
+/// Marks the sectors as allocated on the data of the map sectors and
+/// updates the counters.
+fn set_allocated(data: &mut [u8], addresses: &[Address]) {
+    for address in addresses {
+        let i = address.track * SECTOR_COUNT + address.sector;
+        data[LABEL_SIZE + i / 8] |= 0x80 >> (i % 8);
+    }
+    update_counts(data);
+}
+
+/// Updates the counters of the map sectors from the allocation map.
+fn update_counts(data: &mut [u8]) {
+    let used = data[LABEL_SIZE..LABEL_SIZE + MAP_SIZE].iter()
+        .map(|b| b.count_ones() as usize)
+        .sum::<usize>();
+    let available = TRACK_COUNT * SECTOR_COUNT - used;
+    data[USED_OFFSET..USED_OFFSET + 2].copy_from_slice(&(used as u16).to_le_bytes());
+    data[AVAILABLE_OFFSET..AVAILABLE_OFFSET + 2].copy_from_slice(&(available as u16).to_le_bytes());
+}
+
+/// Entries of a directory sector and the offset after the last one.
+fn directory_entries(data: &[u8]) -> (Vec<(String, Address)>, usize) {
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < SECTOR_SIZE && data[i] != END_OF_DIRECTORY && data[i] != 0 {
+        let length = (data[i] & !SECRET) as usize;
+        if i + length + 3 > SECTOR_SIZE {
+            break;
+        }
+        let name = data[i + 1..i + 1 + length].iter()
+            .map(|&ch| ch as char)
+            .collect();
+        let descriptor = Address::new(data[i + length + 2] as usize, data[i + length + 1] as usize);
+        entries.push((name, descriptor));
+        i += length + 3;
+    }
+    (entries, i)
+}
+
+/// Converts RIO text, lines ended with a carriage return, to host text.
+pub fn to_host_text(content: &[u8]) -> Vec<u8> {
+    content.iter()
+        .map(|&ch| if ch == b'\r' {b'\n'} else {ch})
+        .collect()
+}
+
+/// True for the names accepted for new files, printable ASCII without
+/// spaces.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME && name.bytes().all(|ch| ch.is_ascii_graphic())
+}
+
+/// Name of a RIO file on the host, the host may not accept some names.
+pub fn host_name(name: &str) -> String {
+    name.replace(['/', '\\'], "_")
+}
+
+/// Converts host text to RIO text, lines ended with a carriage return.
+pub fn from_host_text(content: &[u8]) -> Vec<u8> {
+    let mut text = Vec::with_capacity(content.len());
+    for (i, &ch) in content.iter().enumerate() {
+        match ch {
+            b'\r' if content.get(i + 1) == Some(&b'\n') => {},
+            b'\n' => text.push(b'\r'),
+            _ => text.push(ch),
+        }
+    }
+    text
+}
+
+/// Host date as YYMMDD.
+fn today() -> String {
+    let (year, month, day, _) = now();
+    format!("{:02}{:02}{:02}", year % 100, month, day)
+}
+
+/// Host date and time in UTC: year, month, day and seconds of the day.
+pub(crate) fn now() -> (u64, u64, u64, u64) {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    // Civil date from the days since 1970-01-01
+    let days = seconds / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {shifted_month + 3} else {shifted_month - 9};
+    let year = era * 400 + year_of_era + if month <= 2 {1} else {0};
+    (year, month, day, seconds % 86400)
+}
+
+/// Dates are YYMMDD, erased dates are 0xff.
+fn date(data: &[u8]) -> Option<String> {
+    if data.iter().all(|ch| ch.is_ascii_digit()) {
+        Some(data.iter().map(|&ch| ch as char).collect())
+    } else {
+        None
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rio_filesystem() -> Filesystem<Media> {
+        Filesystem::new(Media::new_from_bytes(include_bytes!("../disks/RIO2-2.MCZ")))
+    }
+
+    fn new_filesystem() -> Filesystem<Media> {
+        let mut media = Media::new_from_bytes(&blank_image());
+        media.write_protected = false;
+        let mut fs = Filesystem::new(media);
+        fs.initialize("TEST DISK").unwrap();
+        fs
+    }
+
+    fn empty_filesystem() -> Filesystem<Media> {
+        let mut media = Media::new_from_bytes(include_bytes!("../disks/EMPTY.MCZ"));
+        media.write_protected = false;
+        Filesystem::new(media)
+    }
+
+    #[test]
+    fn reads_the_label_and_the_directory() {
+        let fs = rio_filesystem();
+        assert_eq!(fs.label().unwrap(), "13-3001-03 MCZ RIO 2.2");
+        let files = fs.files().unwrap();
+        assert_eq!(files[0].name, "DIRECTORY");
+        assert!(files.iter().any(|file| file.name == "OS.INIT"));
+        assert!(fs.used_sectors().unwrap() > files.len());
+    }
+
+    #[test]
+    fn reads_the_file_descriptors() {
+        let fs = rio_filesystem();
+        let directory = fs.find("DIRECTORY").unwrap();
+        assert!(directory.is_secret());
+        assert_eq!(directory.type_name(), "D");
+
+        let os = fs.find("OS").unwrap();
+        assert_eq!(os.type_name(), "P");
+        assert_eq!(os.record_count, 6);
+        assert_eq!(os.record_length, 0x400);
+        assert_eq!(os.starting_address, 0x17de);
+        assert_eq!(os.created.as_deref(), Some("791019"));
+
+        assert_eq!(fs.find("MISSING").err().unwrap().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn reads_the_files() {
+        let fs = rio_filesystem();
+        let init = fs.find("OS.INIT").unwrap();
+        assert!(init.is_ascii());
+        assert_eq!(fs.read_file(&init).unwrap(), b"B;\r");
+
+        let os = fs.find("OS").unwrap();
+        assert_eq!(fs.blocks(&os).unwrap().len(), os.record_count);
+        assert_eq!(fs.read_file(&os).unwrap().len(), os.size());
+    }
+
+    #[test]
+    fn text_conversion() {
+        assert_eq!(from_host_text(b"LINE\nEND\n"), b"LINE\rEND\r");
+        assert_eq!(to_host_text(b"LINE\rEND\r"), b"LINE\nEND\n");
+    }
+
+    #[test]
+    fn initialize_writes_the_label_and_the_directory() {
+        let fs = new_filesystem();
+        assert_eq!(fs.label().unwrap(), "TEST DISK");
+        let directory = fs.find(DIRECTORY_NAME).unwrap();
+        assert!(directory.is_secret());
+        assert_eq!(directory.type_name(), "D");
+        assert_eq!(fs.files().unwrap().len(), 1);
+        assert_eq!(fs.used_sectors().unwrap(), DIRECTORY_SECTORS.len() + 1 + MAP_SECTORS);
+    }
+
+    #[test]
+    fn write_file_round_trip() {
+        let mut fs = empty_filesystem();
+        let used = fs.used_sectors().unwrap();
+        let content: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let entry = fs.write_file("DATA.BIN", TYPE_BINARY, &content).unwrap();
+        assert_eq!(entry.size(), content.len());
+        assert_eq!(entry.record_count, 8);
+        assert_eq!(entry.sectors(), 9);
+
+        let file = fs.find("DATA.BIN").unwrap();
+        assert_eq!(file.type_name(), "B");
+        assert_eq!(fs.read_file(&file).unwrap(), content);
+        assert_eq!(fs.used_sectors().unwrap(), used + 9);
+
+        let err = fs.write_file("DATA.BIN", TYPE_BINARY, &content).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn write_file_extends_the_directory() {
+        let mut fs = empty_filesystem();
+        let sectors = fs.directory_sectors().unwrap().len();
+        for i in 0..200 {
+            let name = format!("FILE{:03}", i);
+            fs.write_file(&name, TYPE_ASCII, name.as_bytes()).unwrap();
+        }
+        assert!(fs.directory_sectors().unwrap().len() > sectors);
+        for i in [0, 199] {
+            let name = format!("FILE{:03}", i);
+            let file = fs.find(&name).unwrap();
+            assert_eq!(fs.read_file(&file).unwrap(), name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn write_file_fails_when_full() {
+        let mut fs = empty_filesystem();
+        let free = fs.free_sectors().unwrap().len();
+        let content = vec![0; free * SECTOR_SIZE];
+        assert!(fs.write_file("BIG", TYPE_BINARY, &content).is_err());
+        assert!(fs.find("BIG").is_err());
+    }
+
+    #[test]
+    fn blank_disk_is_invalid() {
+        let fs = Filesystem::new(Media::new_from_bytes(&[0; TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE]));
+        assert!(fs.files().is_err());
+    }
+}
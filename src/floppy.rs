@@ -1,3 +1,5 @@
+use std::io::{ErrorKind, Result};
+
 use iz80::Machine;
 
 use super::mcz_machine::*;
@@ -8,8 +10,28 @@ const RBDIN_ASYNC: u8 = 0x0b;
 const WRTBIN_SYNC: u8 = 0x0e;
 const WRTBIN_ASYNC: u8 = 0x0f;
 
-fn read_disk_sector(machine: &mut MczMachine, media: &Media, address: u16, sector: u8, track: u8) {
-    let data = media.read_sector(track as usize, sector as usize);
+// PROM floppy driver variables and routines used to complete async requests
+const BFFLEN: u16 = 0x12ad;
+const INTPNT: u16 = 0x12af;
+const DSKVSL: u16 = 0x13d0;
+const PRMERR: u16 = 0x09ee; // Stops CLK0 with UNSEL and continues to OPFIN
+const CLK0: u16 = 0xd4;
+const NCRCE: u16 = 0x1393; // Count of CRC errors
+const CRC_RETRIES: u16 = 10;
+const STCLK0: u8 = 0xb7; // Timer with interrupt, prescaler 256
+const COMPLETION_TIME: u8 = 16;
+
+/// Returns true if the PROM driver is busy with an async request.
+pub fn floppy_busy(machine: &MczMachine) -> bool {
+    machine.peek16(DSKVSL) != 0
+}
+
+/// Returns false on CRC error, the data is transferred anyway.
+fn read_disk_sector(machine: &mut MczMachine, volume: usize, address: u16, sector: u8, track: u8) -> bool {
+    let mut data = [0; SECTOR_SIZE_IN_FILE];
+    if let Some(disk) = machine.mdc().drive(volume) {
+        data.copy_from_slice(disk.read_sector(track as usize, sector as usize));
+    }
     for i in 0..SECTOR_SIZE {
         machine.poke(address+i as u16, data[2+i]);
     }
@@ -18,24 +40,27 @@ fn read_disk_sector(machine: &mut MczMachine, media: &Media, address: u16, secto
     for i in 0..6 {
         machine.poke(FLOPPY_POINTERS+i as u16, data[2+SECTOR_SIZE+i]);
     }
+    !crc_error(&data, track as usize, sector as usize)
 }
 
-fn write_disk_sector(machine: &mut MczMachine, media: &mut Media, address: u16, sector: u8, track: u8) {
+fn write_disk_sector(machine: &mut MczMachine, volume: usize, address: u16, sector: u8, track: u8) -> Result<()> {
     let mut data = [0; SECTOR_SIZE_IN_FILE];
-    data[0] = sector & 0x80;
+    data[0] = 0x80 | sector;
     data[1] = track;
     for i in 0..SECTOR_SIZE {
         data[2+i] = machine.peek(address+i as u16);
     }
 
-    // Get the pointers and CRC on the PROM working memory, RIO OS write those bytes.
-    for i in 0..6 {
+    // Get the pointers on the PROM working memory, RIO OS write those bytes.
+    // The controller generates the CRC.
+    for i in 0..4 {
         data[2+SECTOR_SIZE+i] = machine.peek(FLOPPY_POINTERS+i as u16);
     }
-    media.write_sector(track as usize, sector as usize, &data).unwrap();
+    set_sector_crc(&mut data);
+    machine.mdc().write_sector(volume, track as usize, sector as usize, &data)
 }
 
-pub fn rom_floopy(machine: &mut MczMachine, drives: &mut[Media], iy: u16, floppy_trace: bool) -> u16 {
+pub fn rom_floopy(machine: &mut MczMachine, iy: u16, floppy_trace: bool) {
     let request = machine.peek(iy+1);
     let mut data_address = machine.peek16(iy+2);
     let mut data_length = machine.peek16(iy+4) as usize;
@@ -47,48 +72,123 @@ pub fn rom_floopy(machine: &mut MczMachine, drives: &mut[Media], iy: u16, floppy
     let track = machine.peek(iy+12);
 
     if floppy_trace {
-        print!("Floopy: request={:02x} volume={} track={} sector={} data_address={:04x} data_length={} completion_return_address={:04x} error_return_address={:04x}\n",
+        println!("Floopy: request={:02x} volume={} track={} sector={} data_address={:04x} data_length={} completion_return_address={:04x} error_return_address={:04x}",
             request, volume, track, sector, data_address, data_length, completion_return_address, error_return_address);
     }
 
-    if data_length % SECTOR_SIZE != 0{
+    if data_length % SECTOR_SIZE != 0 {
         data_length = (data_length / SECTOR_SIZE + 1) * SECTOR_SIZE;
     }
     let sectors = data_length / SECTOR_SIZE;
-    if sector as usize + sectors > SECTOR_COUNT {
-        panic!("Multi sector read beyond end of track");
-    }
 
     let completion_code: u8;
     let asynch = request == RBDIN_ASYNC || request == WRTBIN_ASYNC;
-    if volume >= drives.len() as u8 {
-        completion_code = 0xc2 // Disk is not ready
+    let write_protected = machine.mdc().drive(volume as usize).map(|disk| disk.is_write_protected());
+    if write_protected.is_none() {
+        completion_code = 0xc2 // Disk is not ready, the drive is empty
+    } else if track as usize >= TRACK_COUNT {
+        completion_code = 0xc1; // Invalid operation request, beyond the last track
+    } else if sector as usize + sectors > SECTOR_COUNT {
+        completion_code = 0xc1; // Invalid operation request, beyond the end of the track
     } else if request == RBDIN_SYNC || request == RBDIN_ASYNC {
-        let media = &drives[volume as usize];
+        let mut bad_crc = false;
         for i in 0..sectors {
-            read_disk_sector(machine, media, data_address, sector + i as u8, track);
+            if !read_disk_sector(machine, volume as usize, data_address, sector + i as u8, track) {
+                bad_crc = true;
+                break;
+            }
             data_address = data_address.wrapping_add(SECTOR_SIZE as u16);
         }
-        completion_code = 0x80; // Normal return
+        if bad_crc {
+            // The PROM counts each of its retries
+            let errors = machine.peek16(NCRCE).wrapping_add(CRC_RETRIES);
+            machine.poke16(NCRCE, errors);
+            completion_code = 0xc6; // CRC error
+        } else {
+            completion_code = 0x80; // Normal return
+        }
     } else if request == WRTBIN_SYNC || request == WRTBIN_ASYNC {
-        let media = &mut drives[volume as usize];
-        for i in 0..sectors {
-            write_disk_sector(machine, media, data_address, sector + i as u8, track);
-            data_address = data_address.wrapping_add(SECTOR_SIZE as u16);
+        if write_protected == Some(true) {
+            completion_code = 0xc3; // Disk is write protected
+        } else {
+            let mut result = Ok(());
+            for i in 0..sectors {
+                result = write_disk_sector(machine, volume as usize, data_address, sector + i as u8, track);
+                if result.is_err() {
+                    break;
+                }
+                data_address = data_address.wrapping_add(SECTOR_SIZE as u16);
+            }
+            completion_code = match result {
+                Ok(()) => 0x80, // Normal return
+                Err(err) => {
+                    eprintln!("Drive {}: {}", volume, err);
+                    if err.kind() == ErrorKind::PermissionDenied {
+                        0xc3 // Disk is write protected
+                    } else {
+                        0xc6 // The host write failed, reported as a CRC error
+                    }
+                },
+            };
         }
-        completion_code = 0x80; // Normal return
-        // Todo, support WP: completion_code = 0xc3; // Disk is write protected
     } else {
         completion_code = 0xc1; // Invalid operation request
     }
 
     machine.poke(iy+10, completion_code);
 
-    if !asynch {
-        0
-    } else if completion_code == 0x80 {
-        completion_return_address
-    } else {
-        error_return_address
+    if asynch {
+        // Complete with the CLK0 interrupt of the PROM driver. RTC0 jumps
+        // to INTPNT and then to the completion or error return address.
+        machine.poke16(DSKVSL, iy);
+        machine.poke16(BFFLEN, 0);
+        machine.poke16(INTPNT, PRMERR);
+        machine.port_out(CLK0, STCLK0);
+        machine.port_out(CLK0, COMPLETION_TIME);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::MemorySerial;
+
+    const REQUEST: u16 = 0x5000;
+    const BUFFER: u16 = 0x6000;
+
+    // Synchronous request on drive 0, returns the completion code
+    fn request(machine: &mut MczMachine, request: u8, sector: u8, track: u8) -> u8 {
+        machine.poke(REQUEST + 1, request);
+        machine.poke16(REQUEST + 2, BUFFER);
+        machine.poke16(REQUEST + 4, SECTOR_SIZE as u16);
+        machine.poke(REQUEST + 11, sector);
+        machine.poke(REQUEST + 12, track);
+        rom_floopy(machine, REQUEST, false);
+        machine.peek(REQUEST + 10)
+    }
+
+    fn new_machine() -> MczMachine {
+        let mut media = Media::new_from_bytes(&blank_image());
+        media.write_protected = false;
+        MczMachine::new(vec![Box::new(media)], Box::new(MemorySerial::new()))
+    }
+
+    #[test]
+    fn write_and_read_a_sector() {
+        let mut machine = new_machine();
+        machine.poke(BUFFER, 0x42);
+        assert_eq!(request(&mut machine, WRTBIN_SYNC, 3, 10), 0x80);
+        machine.poke(BUFFER, 0);
+        assert_eq!(request(&mut machine, RBDIN_SYNC, 3, 10), 0x80);
+        assert_eq!(machine.peek(BUFFER), 0x42);
+    }
+
+    #[test]
+    fn invalid_requests_fail() {
+        let mut machine = new_machine();
+        assert_eq!(request(&mut machine, RBDIN_SYNC, 0, TRACK_COUNT as u8), 0xc1);
+        assert_eq!(request(&mut machine, WRTBIN_SYNC, 0, 0xff), 0xc1);
+        // Drive 1 is empty
+        assert_eq!(request(&mut machine, RBDIN_SYNC, 1 << 5, 0), 0xc2);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use super::filesystem::*;
+use super::media::*;
+
+/*
+Host directory mounted as a RIO disk. The volume is built in memory when
+mounted: an empty RIO directory labeled with the directory name, and a
+file for each host file with a valid RIO name. The text files are ASCII
+files with carriage returns as line ends, the others are binary files.
+
+The writes of RIO change the volume in memory. After each write on the
+directory, on the allocation map or on a file descriptor the files are
+read again and the changes are written back: the files with new content
+are written on the host and the files deleted are removed. The files are
+read when complete, the writes of the data of a file are seen when RIO
+updates the descriptor on close. The host files changed while mounted are
+not seen by RIO, and the types and properties set by RIO are not kept.
+*/
+
+const DEFAULT_LABEL: &str = "HOST DIRECTORY";
+const MAX_LABEL: usize = 24;
+
+pub struct HostDirectory {
+    path: PathBuf,
+    fs: Filesystem<Media>,
+    // Content of each file as last written on the host
+    files: HashMap<String, Vec<u8>>,
+    descriptors: HashSet<Address>,
+    pub write_protected: bool,
+    write_back: bool,
+}
+
+impl HostDirectory {
+    /// Mounts a host directory. Without write back the writes of RIO are
+    /// kept in memory only.
+    pub fn open(path: &str, write_back: bool) -> Result<HostDirectory> {
+        let path = PathBuf::from(path);
+        let mut media = Media::new_from_bytes(&blank_image());
+        media.write_protected = false;
+        let mut fs = Filesystem::new(media);
+        fs.initialize(&label(&path))?;
+
+        let mut entries = fs::read_dir(&path)?
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type().is_ok_and(|file_type| file_type.is_file()) || !is_valid_name(&name) {
+                continue;
+            }
+            // A file that can't be read or doesn't fit is left out
+            let result = fs::read(entry.path()).and_then(|content| if is_text(&content) {
+                fs.write_file(&name, TYPE_ASCII, &from_host_text(&content))
+            } else {
+                fs.write_file(&name, TYPE_BINARY, &content)
+            });
+            if let Err(err) = result {
+                eprintln!("{}: {} not mounted: {}", path.display(), name, err);
+            }
+        }
+
+        let mut directory = HostDirectory {
+            path,
+            fs,
+            files: HashMap::new(),
+            descriptors: HashSet::new(),
+            write_protected: false,
+            write_back,
+        };
+        for (file, content) in directory.scan()? {
+            directory.descriptors.insert(file.descriptor);
+            directory.files.insert(file.name, content.unwrap_or_default());
+        }
+        Ok(directory)
+    }
+
+    /// Files of the volume with their content, if they can be read. The
+    /// files being written may not.
+    fn scan(&self) -> Result<Vec<(FileEntry, Option<Vec<u8>>)>> {
+        Ok(self.fs.files()?.into_iter()
+            .filter(|file| file.descriptor != Address::new(DIRECTORY_TRACK, 0))
+            .map(|file| {
+                let content = self.fs.read_file(&file).ok();
+                (file, content)
+            })
+            .collect())
+    }
+
+    /// Writes the changed files on the host and removes the deleted ones.
+    fn write_back(&mut self) -> Result<()> {
+        let files = self.scan()?;
+        self.descriptors = files.iter().map(|(file, _)| file.descriptor).collect();
+
+        for (file, content) in files.iter() {
+            let content = match content {
+                Some(content) if self.files.get(&file.name) != Some(content) => content,
+                _ => continue,
+            };
+            let host_content = if file.is_ascii() {
+                to_host_text(content)
+            } else {
+                content.clone()
+            };
+            fs::write(self.path.join(host_name(&file.name)), host_content)?;
+            self.files.insert(file.name.clone(), content.clone());
+        }
+
+        let deleted: Vec<String> = self.files.keys()
+            .filter(|name| !files.iter().any(|(file, _)| file.name == **name))
+            .cloned()
+            .collect();
+        for name in deleted {
+            match fs::remove_file(self.path.join(host_name(&name))) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {},
+            }
+            self.files.remove(&name);
+        }
+        Ok(())
+    }
+}
+
+impl Disk for HostDirectory {
+    fn read_sector(&self, track: usize, sector: usize) -> &[u8] {
+        self.fs.disk().read_sector(track, sector)
+    }
+
+    fn write_sector(&mut self, track: usize, sector: usize, data: &[u8]) -> Result<()> {
+        if self.write_protected {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Disk is write protected"));
+        }
+        self.fs.disk_mut().write_sector(track, sector, data)?;
+
+        let metadata = track == DIRECTORY_TRACK || track == MAP_TRACK
+            || self.descriptors.contains(&Address::new(track, sector));
+        if self.write_back && metadata {
+            // The sector is written, a host error doesn't fail the disk write
+            if let Err(err) = self.write_back() {
+                eprintln!("{}: {}", self.path.display(), err);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+}
+
+/// Volume label from the directory name.
+fn label(path: &Path) -> String {
+    let name = path.canonicalize().ok()
+        .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    let label: String = name.chars()
+        .filter(|ch| ch.is_ascii_graphic() || *ch == ' ')
+        .take(MAX_LABEL)
+        .collect();
+    if label.is_empty() {
+        DEFAULT_LABEL.to_string()
+    } else {
+        label
+    }
+}
+
+/// Printable ASCII with tabs, line ends and form feeds.
+fn is_text(content: &[u8]) -> bool {
+    content.iter().all(|&ch| ch.is_ascii_graphic() || b" \t\r\n\x0c".contains(&ch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("izilogmcz-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("NOTES.TXT"), b"LINE 1\nLINE 2\n").unwrap();
+        fs::write(path.join("DATA.BIN"), [0, 1, 2, 0xff]).unwrap();
+        fs::write(path.join("WITH SPACE"), b"SKIPPED\n").unwrap();
+        fs::create_dir(path.join("SUBDIR")).unwrap();
+        path
+    }
+
+    fn mount(path: &Path, write_back: bool) -> Filesystem<HostDirectory> {
+        Filesystem::new(HostDirectory::open(path.to_str().unwrap(), write_back).unwrap())
+    }
+
+    #[test]
+    fn mounts_the_host_files() {
+        let path = temp_directory("mount");
+        let fs = mount(&path, false);
+        let names: Vec<String> = fs.files().unwrap().into_iter()
+            .filter(|file| !file.is_secret())
+            .map(|file| file.name)
+            .collect();
+        assert_eq!(names, ["DATA.BIN", "NOTES.TXT"]);
+
+        let notes = fs.find("NOTES.TXT").unwrap();
+        assert!(notes.is_ascii());
+        assert_eq!(fs.read_file(&notes).unwrap(), b"LINE 1\rLINE 2\r");
+        let data = fs.find("DATA.BIN").unwrap();
+        assert!(!data.is_ascii());
+        assert_eq!(fs.read_file(&data).unwrap(), [0, 1, 2, 0xff]);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn skips_the_files_that_dont_fit() {
+        let path = temp_directory("too-big");
+        fs::write(path.join("BIG.BIN"), vec![0; TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE]).unwrap();
+        let fs = mount(&path, false);
+        assert!(fs.find("BIG.BIN").is_err());
+        assert!(fs.find("DATA.BIN").is_ok());
+        assert!(fs.find("NOTES.TXT").is_ok());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_back_the_new_files() {
+        let path = temp_directory("write-back");
+        let mut fs = mount(&path, true);
+        fs.write_file("NEW.TXT", TYPE_ASCII, b"HELLO\r").unwrap();
+        assert_eq!(fs::read(path.join("NEW.TXT")).unwrap(), b"HELLO\n");
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_writes_in_memory() {
+        let path = temp_directory("in-memory");
+        let mut fs = mount(&path, false);
+        fs.write_file("NEW.TXT", TYPE_ASCII, b"HELLO\r").unwrap();
+        assert!(fs.find("NEW.TXT").is_ok());
+        assert!(!path.join("NEW.TXT").exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn detects_text() {
+        assert!(is_text(b"A\tB\r\n\x0c"));
+        assert!(!is_text(b"A\0B"));
+    }
+}
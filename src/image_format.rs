@@ -0,0 +1,281 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use super::filesystem;
+use super::media::*;
+
+/*
+Image file formats. The emulator works with the MCZ format, the others are
+converted when loaded and saved:
+ - MCZ: the 136 bytes of each sector, with the header, the data, the
+   pointers and the CRC, in track and sector order.
+ - Raw: the 128 bytes of data of each sector, in the same order.
+ - IMD: ImageDisk. A header line and a comment ended by 0x1a, then a
+   record per track with the mode, the cylinder, the head, the sector
+   count, the size code, 0 for 128 bytes, and the sector numbers. Bits 7
+   and 6 of the head flag the optional maps of cylinders and heads of each
+   sector. The data of each sector starts with a type: 0 if unavailable,
+   odd for the bytes of the sector and even for a byte filling the sector.
+
+The raw and IMD images only have the data. The headers and the CRCs are
+generated, the pointers are lost and written as the end of the chain.
+*/
+
+const IMD_SIGNATURE: &[u8] = b"IMD ";
+const IMD_COMMENT_END: u8 = 0x1a;
+const IMD_MODE_FM_500: u8 = 0; // 8 inch single density
+const IMD_CYLINDER_MAP: u8 = 0x80;
+const IMD_HEAD_MAP: u8 = 0x40;
+const IMD_SIZE_128: u8 = 0;
+const IMD_UNAVAILABLE: u8 = 0;
+const IMD_DATA: u8 = 1;
+const IMD_COMPRESSED: u8 = 2;
+const IMD_LAST_TYPE: u8 = 8;
+
+const END_OF_CHAIN: [u8; 4] = [0xff; 4];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageFormat {
+    Mcz,
+    Raw,
+    Imd,
+}
+
+impl ImageFormat {
+    /// Format of the content of an image file.
+    pub fn detect(content: &[u8]) -> Option<ImageFormat> {
+        if content.starts_with(IMD_SIGNATURE) {
+            Some(ImageFormat::Imd)
+        } else if content.len() == TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE {
+            Some(ImageFormat::Mcz)
+        } else if content.len() == TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE {
+            Some(ImageFormat::Raw)
+        } else {
+            None
+        }
+    }
+
+    /// Format by name, mcz, raw or imd in any case.
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "mcz" => Some(ImageFormat::Mcz),
+            "raw" => Some(ImageFormat::Raw),
+            "imd" => Some(ImageFormat::Imd),
+            _ => None,
+        }
+    }
+
+    /// Format by the extension of the file name.
+    pub fn from_filename(filename: &str) -> Option<ImageFormat> {
+        Path::new(filename).extension()
+            .and_then(|extension| ImageFormat::from_name(&extension.to_string_lossy()))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Mcz => "MCZ",
+            ImageFormat::Raw => "raw",
+            ImageFormat::Imd => "IMD",
+        }
+    }
+
+    /// True if the format keeps the headers, pointers and CRCs.
+    pub fn has_trailers(&self) -> bool {
+        *self == ImageFormat::Mcz
+    }
+
+    /// Converts the content of an image file to the MCZ format.
+    pub fn decode(&self, content: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ImageFormat::Mcz => {
+                if ImageFormat::detect(content) != Some(ImageFormat::Mcz) {
+                    return Err(invalid(format!("Invalid MCZ image size {}", content.len())));
+                }
+                Ok(content.to_vec())
+            },
+            ImageFormat::Raw => {
+                if ImageFormat::detect(content) != Some(ImageFormat::Raw) {
+                    return Err(invalid(format!("Invalid raw image size {}", content.len())));
+                }
+                Ok(from_data(content))
+            },
+            ImageFormat::Imd => decode_imd(content),
+        }
+    }
+
+    /// Converts an image in the MCZ format to this format.
+    pub fn encode(&self, content: &[u8]) -> Vec<u8> {
+        match self {
+            ImageFormat::Mcz => content.to_vec(),
+            ImageFormat::Raw => content.chunks(SECTOR_SIZE_IN_FILE)
+                .flat_map(|sector| sector[2..2 + SECTOR_SIZE].iter().copied())
+                .collect(),
+            ImageFormat::Imd => encode_imd(content),
+        }
+    }
+}
+
+/// Detects the format of the content of an image file and converts it to
+/// the MCZ format.
+pub fn load(content: &[u8]) -> Result<(ImageFormat, Vec<u8>)> {
+    let format = ImageFormat::detect(content).ok_or_else(||
+        Error::other(format!("Unrecognized disk image format (len {})", content.len())))?;
+    Ok((format, format.decode(content)?))
+}
+
+/// Builds the sectors from the data of each one.
+fn from_data(data: &[u8]) -> Vec<u8> {
+    let mut content = vec![0; TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE];
+    for (i, (sector, data)) in content.chunks_mut(SECTOR_SIZE_IN_FILE)
+            .zip(data.chunks(SECTOR_SIZE)).enumerate() {
+        sector[0] = 0x80 | (i % SECTOR_COUNT) as u8;
+        sector[1] = (i / SECTOR_COUNT) as u8;
+        sector[2..2 + SECTOR_SIZE].copy_from_slice(data);
+        sector[2 + SECTOR_SIZE..CRC_OFFSET].copy_from_slice(&END_OF_CHAIN);
+        set_sector_crc(sector);
+    }
+    content
+}
+
+fn decode_imd(content: &[u8]) -> Result<Vec<u8>> {
+    let mut data = vec![0; TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE];
+    let mut position = content.iter().position(|&byte| byte == IMD_COMMENT_END)
+        .ok_or_else(|| invalid("IMD comment without end".to_string()))? + 1;
+
+    while position < content.len() {
+        let track = take(content, &mut position, 5)?;
+        let (cylinder, head, count, size) = (track[1] as usize, track[2], track[3] as usize, track[4]);
+        let numbers = take(content, &mut position, count)?;
+        if head & IMD_CYLINDER_MAP != 0 {
+            take(content, &mut position, count)?;
+        }
+        if head & IMD_HEAD_MAP != 0 {
+            take(content, &mut position, count)?;
+        }
+        if size != IMD_SIZE_128 {
+            return Err(invalid(format!("Unsupported IMD sector size code {} on cylinder {}", size, cylinder)));
+        }
+
+        // Some captures number the sectors from 1
+        let first = if numbers.contains(&0) {0} else {1};
+        for &number in numbers {
+            let sector_type = take(content, &mut position, 1)?[0];
+            let sector_data = match sector_type {
+                IMD_UNAVAILABLE => continue,
+                t if t > IMD_LAST_TYPE => return Err(invalid(format!("Invalid IMD sector type {}", t))),
+                t if t % 2 == 1 => take(content, &mut position, SECTOR_SIZE)?.to_vec(),
+                _ => vec![take(content, &mut position, 1)?[0]; SECTOR_SIZE],
+            };
+            // Single sided, the other head is ignored
+            let sector = (number as usize).wrapping_sub(first);
+            if head & !(IMD_CYLINDER_MAP | IMD_HEAD_MAP) == 0 && cylinder < TRACK_COUNT && sector < SECTOR_COUNT {
+                let start = (cylinder * SECTOR_COUNT + sector) * SECTOR_SIZE;
+                data[start..start + SECTOR_SIZE].copy_from_slice(&sector_data);
+            }
+        }
+    }
+    Ok(from_data(&data))
+}
+
+fn encode_imd(content: &[u8]) -> Vec<u8> {
+    let (year, month, day, seconds) = filesystem::now();
+    let mut imd = format!("IMD 1.18: {:02}/{:02}/{:04} {:02}:{:02}:{:02}\r\n",
+        day, month, year, seconds / 3600, seconds / 60 % 60, seconds % 60).into_bytes();
+    imd.extend_from_slice(b"Zilog MCZ-1 disk\r\n");
+    imd.push(IMD_COMMENT_END);
+
+    for (track, sectors) in content.chunks(SECTOR_COUNT * SECTOR_SIZE_IN_FILE).enumerate() {
+        imd.extend_from_slice(&[IMD_MODE_FM_500, track as u8, 0, SECTOR_COUNT as u8, IMD_SIZE_128]);
+        imd.extend(0..SECTOR_COUNT as u8);
+        for sector in sectors.chunks(SECTOR_SIZE_IN_FILE) {
+            let data = &sector[2..2 + SECTOR_SIZE];
+            if data.iter().all(|&byte| byte == data[0]) {
+                imd.extend_from_slice(&[IMD_COMPRESSED, data[0]]);
+            } else {
+                imd.push(IMD_DATA);
+                imd.extend_from_slice(data);
+            }
+        }
+    }
+    imd
+}
+
+/// Next bytes of the content, fails if truncated.
+fn take<'a>(content: &'a [u8], position: &mut usize, count: usize) -> Result<&'a [u8]> {
+    let bytes = content.get(*position..*position + count)
+        .ok_or_else(|| invalid("Truncated IMD image".to_string()))?;
+    *position += count;
+    Ok(bytes)
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static RIO_206: &[u8] = include_bytes!("../disks/13-3001-01_MCZ1-20_RIO_206.MCZ");
+
+    fn data(content: &[u8]) -> Vec<u8> {
+        ImageFormat::Raw.encode(content)
+    }
+
+    fn round_trip(format: ImageFormat) {
+        let encoded = format.encode(RIO_206);
+        assert_eq!(ImageFormat::detect(&encoded), Some(format));
+        let (detected, decoded) = load(&encoded).unwrap();
+        assert_eq!(detected, format);
+        assert_eq!(data(&decoded), data(RIO_206));
+
+        // Generated headers, pointers as end of chain and CRCs
+        for (i, sector) in decoded.chunks(SECTOR_SIZE_IN_FILE).enumerate() {
+            assert_eq!(sector[..2], [0x80 | (i % SECTOR_COUNT) as u8, (i / SECTOR_COUNT) as u8]);
+            assert_eq!(sector[2 + SECTOR_SIZE..CRC_OFFSET], END_OF_CHAIN);
+            assert!(!crc_error(sector, i / SECTOR_COUNT, i % SECTOR_COUNT));
+        }
+        assert_eq!(format.decode(&format.encode(&decoded)).unwrap(), decoded);
+    }
+
+    #[test]
+    fn mcz_is_kept() {
+        assert_eq!(load(RIO_206).unwrap(), (ImageFormat::Mcz, RIO_206.to_vec()));
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        round_trip(ImageFormat::Raw);
+    }
+
+    #[test]
+    fn imd_round_trip() {
+        round_trip(ImageFormat::Imd);
+    }
+
+    #[test]
+    fn imd_sectors_from_1_and_compressed() {
+        let mut imd = b"IMD 1.18: test\r\n".to_vec();
+        imd.push(IMD_COMMENT_END);
+        imd.extend_from_slice(&[IMD_MODE_FM_500, 2, 0, 2, IMD_SIZE_128, 1, 2]);
+        imd.push(IMD_DATA);
+        imd.extend((0..SECTOR_SIZE).map(|i| i as u8));
+        imd.extend_from_slice(&[IMD_COMPRESSED, 0xe5]);
+
+        let (_, content) = load(&imd).unwrap();
+        let start = 2 * SECTOR_COUNT * SECTOR_SIZE_IN_FILE;
+        let sector = &content[start..start + SECTOR_SIZE_IN_FILE];
+        assert_eq!(sector[..2], [0x80, 2]);
+        assert!(sector[2..2 + SECTOR_SIZE].iter().enumerate().all(|(i, &byte)| byte == i as u8));
+        let sector = &content[start + SECTOR_SIZE_IN_FILE..start + 2 * SECTOR_SIZE_IN_FILE];
+        assert!(sector[2..2 + SECTOR_SIZE].iter().all(|&byte| byte == 0xe5));
+    }
+
+    #[test]
+    fn invalid_images_fail() {
+        assert!(load(&RIO_206[1..]).is_err());
+        let imd = ImageFormat::Imd.encode(RIO_206);
+        assert!(load(&imd[..imd.len() - 1]).is_err());
+        assert!(load(b"IMD without end").is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use std::io::Result;
+
+use iz80::*;
+
+use super::mcz_machine::MczMachine;
+use super::snapshot::{Reader, Writer};
+
+/*
+Maskable interrupts. iz80 only supports NMI, the interrupt enable flip-flops,
+the interrupt mode and the HALT state are tracked here by decoding the
+instructions before iz80 executes them.
+
+The devices are connected in a daisy chain. A device with an interrupt
+under service blocks the interrupts of the devices with lower priority
+until the RETI instruction.
+*/
+
+/// Device in the interrupt daisy chain
+pub trait InterruptDevice {
+    /// Returns true if the device has an interrupt waiting to be acknowledged
+    fn int_pending(&self) -> bool;
+    /// Returns true if the device has an interrupt under service
+    fn int_in_service(&self) -> bool;
+    /// Acknowledges the pending interrupt and returns the vector
+    fn int_ack(&mut self) -> u8;
+    /// Ends the interrupt under service, the CPU has executed a RETI
+    fn reti(&mut self);
+}
+
+const NMI_ADDRESS: u16 = 0x0066;
+
+pub struct Interrupts {
+    iff1: bool,
+    iff2: bool,
+    mode: u8,
+    ei_delay: bool,
+    halted: bool,
+    load_iff: bool,
+}
+
+impl Interrupts {
+    pub fn new() -> Interrupts {
+        Interrupts {
+            iff1: false,
+            iff2: false,
+            mode: 0,
+            ei_delay: false,
+            halted: false,
+            load_iff: false,
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.iff1
+    }
+
+    pub(crate) fn save_state(&self, writer: &mut Writer) {
+        writer.bool(self.iff1);
+        writer.bool(self.iff2);
+        writer.u8(self.mode);
+        writer.bool(self.ei_delay);
+        writer.bool(self.halted);
+        writer.bool(self.load_iff);
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        self.iff1 = reader.bool()?;
+        self.iff2 = reader.bool()?;
+        self.mode = reader.u8()?;
+        self.ei_delay = reader.bool()?;
+        self.halted = reader.bool()?;
+        self.load_iff = reader.bool()?;
+        Ok(())
+    }
+
+    /// Accepts the pending interrupt of the daisy chain if the interrupts
+    /// are enabled. Returns true if the interrupt has been accepted.
+    pub fn accept(&mut self, cpu: &mut Cpu, machine: &mut MczMachine) -> bool {
+        if !self.iff1 || self.ei_delay || !machine.interrupt_request() {
+            return false;
+        }
+
+        let vector = machine.interrupt_ack();
+        let address = match self.mode {
+            0 => (vector & 0x38) as u16, // The device provides a RST instruction
+            1 => 0x0038,
+            _ => {
+                let table = (cpu.registers().get8(Reg8::I) as u16) << 8 | (vector & 0xfe) as u16;
+                machine.peek16(table)
+            }
+        };
+
+        self.iff1 = false;
+        self.iff2 = false;
+        self.call(cpu, machine, address);
+        true
+    }
+
+    /// Non maskable interrupt. It is not signaled to iz80, as iz80 would
+    /// execute the first instruction of the handler without decoding it.
+    pub fn nmi(&mut self, cpu: &mut Cpu, machine: &mut MczMachine) {
+        self.iff2 = self.iff1;
+        self.iff1 = false;
+        self.call(cpu, machine, NMI_ADDRESS);
+    }
+
+    /// Updates the state with the instruction about to be executed. Returns
+    /// false for HALT, it has to be skipped as iz80 would stop forever.
+    pub fn decode(&mut self, cpu: &mut Cpu, machine: &mut MczMachine) -> bool {
+        // EI enables the interrupts after the next instruction
+        self.ei_delay = false;
+
+        let pc = cpu.registers().pc();
+        let mut address = pc;
+        let mut opcode = machine.peek_unmonitored(address);
+        while opcode == 0xdd || opcode == 0xfd {
+            address = address.wrapping_add(1);
+            opcode = machine.peek_unmonitored(address);
+        }
+
+        match opcode {
+            0xf3 /*DI*/ => {
+                self.iff1 = false;
+                self.iff2 = false;
+            },
+            0xfb /*EI*/ => {
+                self.iff1 = true;
+                self.iff2 = true;
+                self.ei_delay = true;
+            },
+            0x76 /*HALT*/ => {
+                self.halted = true;
+                cpu.registers().set_pc(address.wrapping_add(1));
+                return false;
+            },
+            0xed => match machine.peek_unmonitored(address.wrapping_add(1)) {
+                0x4d /*RETI*/ => machine.reti(),
+                0x45 | 0x55 | 0x5d | 0x65 | 0x6d | 0x75 | 0x7d /*RETN*/ => self.iff1 = self.iff2,
+                0x46 | 0x4e | 0x66 | 0x6e /*IM 0*/ => self.mode = 0,
+                0x56 | 0x76 /*IM 1*/ => self.mode = 1,
+                0x5e | 0x7e /*IM 2*/ => self.mode = 2,
+                0x57 | 0x5f /*LD A,I and LD A,R*/ => self.load_iff = true,
+                _ => {}
+            },
+            _ => {}
+        }
+        true
+    }
+
+    /// Completes the instruction executed by iz80.
+    pub fn executed(&mut self, cpu: &mut Cpu) {
+        if self.load_iff {
+            // LD A,I and LD A,R copy IFF2 to the parity flag
+            self.load_iff = false;
+            cpu.registers().put_flag(Flag::P, self.iff2);
+        }
+    }
+
+    fn call(&mut self, cpu: &mut Cpu, machine: &mut MczMachine, address: u16) {
+        self.halted = false;
+        let pc = cpu.registers().pc();
+        let sp = cpu.registers().get16(Reg16::SP).wrapping_sub(2);
+        machine.poke16(sp, pc);
+        cpu.registers().set16(Reg16::SP, sp);
+        cpu.registers().set_pc(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::MemorySerial;
+
+    const PROGRAM: u16 = 0x4000;
+    const HANDLER: u16 = 0x4100;
+
+    fn machine_with(program: &[u8]) -> (Cpu, MczMachine, Interrupts) {
+        let mut machine = MczMachine::new(Vec::new(), Box::new(MemorySerial::new()));
+        for (i, &byte) in program.iter().enumerate() {
+            machine.poke(PROGRAM + i as u16, byte);
+        }
+        let mut cpu = Cpu::new_z80();
+        cpu.registers().set_pc(PROGRAM);
+        cpu.registers().set16(Reg16::SP, 0x8000);
+        (cpu, machine, Interrupts::new())
+    }
+
+    // Same sequence as the emulation loop
+    fn step(cpu: &mut Cpu, machine: &mut MczMachine, interrupts: &mut Interrupts) -> bool {
+        let accepted = interrupts.accept(cpu, machine);
+        if !interrupts.is_halted() && interrupts.decode(cpu, machine) {
+            cpu.execute_instruction(machine);
+            interrupts.executed(cpu);
+        }
+        accepted
+    }
+
+    // CTC channel 1 interrupts every 16 cycles with vector 0x12
+    fn start_timer(machine: &mut MczMachine) {
+        machine.port_out(0xd4, 0x10);
+        machine.port_out(0xd5, 0x87);
+        machine.port_out(0xd5, 1);
+        machine.advance(16);
+    }
+
+    #[test]
+    fn mode_2_interrupt_after_the_ei_delay() {
+        // IM 2; EI; NOP; NOP
+        let (mut cpu, mut machine, mut interrupts) = machine_with(&[0xed, 0x5e, 0xfb, 0x00, 0x00]);
+        cpu.registers().set8(Reg8::I, 0x50);
+        machine.poke16(0x5012, HANDLER);
+        start_timer(&mut machine);
+
+        assert!(!step(&mut cpu, &mut machine, &mut interrupts)); // IM 2
+        assert!(!step(&mut cpu, &mut machine, &mut interrupts)); // EI
+        assert!(!step(&mut cpu, &mut machine, &mut interrupts)); // NOP, delayed
+        assert!(step(&mut cpu, &mut machine, &mut interrupts));
+        assert_eq!(cpu.registers().pc(), HANDLER + 1);
+        assert_eq!(machine.peek16(0x7ffe), PROGRAM + 4);
+        assert!(!interrupts.is_enabled());
+    }
+
+    #[test]
+    fn reti_ends_the_service() {
+        // IM 2; EI; NOP; then the handler: EI; RETI
+        let (mut cpu, mut machine, mut interrupts) = machine_with(&[0xed, 0x5e, 0xfb, 0x00, 0x00, 0x00]);
+        for (i, &byte) in [0xfb, 0xed, 0x4d].iter().enumerate() {
+            machine.poke(HANDLER + i as u16, byte);
+        }
+        cpu.registers().set8(Reg8::I, 0x50);
+        machine.poke16(0x5012, HANDLER);
+        start_timer(&mut machine);
+        for _ in 0..4 {
+            step(&mut cpu, &mut machine, &mut interrupts);
+        }
+        assert_eq!(cpu.registers().pc(), HANDLER + 1);
+
+        // The next interrupt waits for the RETI
+        machine.advance(16);
+        assert!(!machine.interrupt_request());
+        assert!(!step(&mut cpu, &mut machine, &mut interrupts)); // RETI
+        assert_eq!(cpu.registers().pc(), PROGRAM + 4);
+        assert!(machine.interrupt_request());
+        assert!(step(&mut cpu, &mut machine, &mut interrupts));
+    }
+
+    #[test]
+    fn halt_waits_for_an_interrupt() {
+        // IM 1; EI; HALT
+        let (mut cpu, mut machine, mut interrupts) = machine_with(&[0xed, 0x56, 0xfb, 0x76, 0x00]);
+        for _ in 0..3 {
+            step(&mut cpu, &mut machine, &mut interrupts);
+        }
+        assert!(interrupts.is_halted());
+        assert_eq!(cpu.registers().pc(), PROGRAM + 4);
+        step(&mut cpu, &mut machine, &mut interrupts);
+        assert!(interrupts.is_halted());
+
+        start_timer(&mut machine);
+        assert!(step(&mut cpu, &mut machine, &mut interrupts));
+        assert!(!interrupts.is_halted());
+        assert_eq!(machine.peek16(0x7ffe), PROGRAM + 4);
+    }
+
+    #[test]
+    fn ld_a_i_copies_iff2_to_parity() {
+        // EI; LD A,I; DI; LD A,I
+        let (mut cpu, mut machine, mut interrupts) = machine_with(&[0xfb, 0xed, 0x57, 0xf3, 0xed, 0x57]);
+        step(&mut cpu, &mut machine, &mut interrupts);
+        step(&mut cpu, &mut machine, &mut interrupts);
+        assert!(cpu.registers().get_flag(Flag::P));
+        step(&mut cpu, &mut machine, &mut interrupts);
+        step(&mut cpu, &mut machine, &mut interrupts);
+        assert!(!cpu.registers().get_flag(Flag::P));
+    }
+
+    #[test]
+    fn nmi_saves_iff1_in_iff2() {
+        // EI; NOP
+        let (mut cpu, mut machine, mut interrupts) = machine_with(&[0xfb, 0x00]);
+        step(&mut cpu, &mut machine, &mut interrupts);
+        interrupts.nmi(&mut cpu, &mut machine);
+        assert_eq!(cpu.registers().pc(), NMI_ADDRESS);
+        assert_eq!(machine.peek16(0x7ffe), PROGRAM + 1);
+        assert!(!interrupts.is_enabled());
+
+        // The NMI handler is in the ROM, RETN restores IFF1
+        cpu.registers().set_pc(HANDLER);
+        machine.poke(HANDLER, 0xed);
+        machine.poke(HANDLER + 1, 0x45);
+        step(&mut cpu, &mut machine, &mut interrupts);
+        assert_eq!(cpu.registers().pc(), PROGRAM + 1);
+        assert!(interrupts.is_enabled());
+    }
+}
@@ -0,0 +1,57 @@
+//! Emulation of the Zilog MCZ-1 computer.
+//!
+//! The [Emulator] runs headless, the console is any [SerialDevice] and the
+//! drives any [Disk]. The binary connects them to the host terminal.
+
+mod batch;
+mod ctc;
+mod debugger;
+mod emulator;
+mod floppy;
+mod host_directory;
+mod interrupts;
+mod mcz_machine;
+mod mdc;
+mod pio;
+mod printer;
+mod replay;
+mod script;
+#[cfg(unix)]
+mod pty;
+mod serial;
+mod telnet;
+mod timing;
+
+pub mod check;
+pub mod control;
+pub mod filesystem;
+pub mod image_format;
+pub mod media;
+pub mod overlay;
+pub mod snapshot;
+
+#[cfg(windows)]
+mod console_windows;
+#[cfg(unix)]
+mod console_unix;
+
+pub use self::batch::Batch;
+#[cfg(windows)]
+pub use self::console_windows::Console;
+#[cfg(unix)]
+pub use self::console_unix::Console;
+pub use self::debugger::{Action, Debugger};
+pub use self::emulator::{Emulator, CLOCK_HZ};
+pub use self::filesystem::Filesystem;
+pub use self::host_directory::HostDirectory;
+pub use self::mcz_machine::{Access, MczMachine};
+pub use self::mdc::MAX_DRIVES;
+pub use self::media::{Disk, Media};
+pub use self::pio::ParallelDevice;
+pub use self::printer::Printer;
+pub use self::replay::{Player, Recorder};
+pub use self::script::Script;
+#[cfg(unix)]
+pub use self::pty::PtySerial;
+pub use self::serial::{MemorySerial, SerialDevice, StreamSerial};
+pub use self::telnet::TelnetSerial;
@@ -1,18 +1,14 @@
-use clap::{Arg, App};
-use iz80::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{stdout, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::process;
 
-mod floppy;
-mod media;
-mod mcz_machine;
+use clap::{Arg, App, SubCommand};
 
-#[cfg(windows)]
-mod console_windows;
-#[cfg(unix)]
-mod console_unix;
-
-use self::floppy::rom_floopy;
-use self::media::Media;
-use self::mcz_machine::*;
+use izilogmcz::*;
+use izilogmcz::control::{Command, Control, Request};
+use izilogmcz::image_format::ImageFormat;
+use izilogmcz::media::{SECTOR_COUNT, TRACK_COUNT};
 
 // Welcome message
 const WELCOME: &str =
@@ -34,20 +30,333 @@ static DISK_PASCAL: &[u8] = include_bytes!("../disks/13-3371-02__MCZ_PDS_PASCAL_
 //static DISK_2_2_SYSTEM: &[u8] = include_bytes!("../disks/13-1000-01-UNABRIDGED_SYSTEM_DISK.MCZ");
 static DISK_EMPTY: &[u8] = include_bytes!("../disks/EMPTY.MCZ");
 
-fn interrupt(cpu: &mut Cpu, machine: &mut MczMachine, dest: u16) {
-    let pc = cpu.registers().pc();
-    let sp = cpu.registers().get16(Reg16::SP);
-    machine.poke(sp-2, pc as u8);
-    machine.poke(sp-1, (pc >> 8) as u8);
-    cpu.registers().set16(Reg16::SP, sp-2);
-    cpu.registers().set_pc(dest);
+// Instructions between checks of the control commands and the debugger key
+const HOST_POLL_INSTRUCTIONS: u32 = 10000;
+
+/// Parses a key as ^X for a control key, as a hex value like 0x1d or as
+/// a single char. "none" disables the key. Exits on an invalid key.
+fn parse_key(key: &str) -> Option<u8> {
+    let bytes = key.as_bytes();
+    if key == "none" {
+        None
+    } else if bytes.len() == 2 && bytes[0] == b'^' {
+        Some(bytes[1].to_ascii_uppercase() & 0x1f)
+    } else if let Some(hex) = key.strip_prefix("0x") {
+        match u8::from_str_radix(hex, 16) {
+            Ok(code) => Some(code),
+            Err(_) => {
+                eprintln!("Invalid key code {}, expected a hex value like 0x1d", key);
+                process::exit(1);
+            }
+        }
+    } else if bytes.len() == 1 {
+        Some(bytes[0])
+    } else {
+        eprintln!("Invalid key {}, expected ^X, a hex value like 0x1d, a single char or none", key);
+        process::exit(1);
+    }
+}
+
+/// Creates the console device: "terminal" for the host terminal,
+/// "tcp:HOST:PORT" to connect to a TCP server, "telnet:[HOST:]PORT" to
+/// serve telnet clients, "pty" for a pseudo-terminal or "file:INPUT[,OUTPUT]"
+/// for files or named pipes, the output defaults to stdout.
+fn serial_device(spec: &str, break_key: Option<u8>, debug_key: Option<u8>) -> Result<Box<dyn SerialDevice>> {
+    let (kind, params) = match spec.find(':') {
+        Some(i) => (&spec[..i], &spec[i+1..]),
+        None => (spec, ""),
+    };
+    match kind {
+        "terminal" => {
+            let mut console = Console::new(break_key);
+            console.set_debug_key(debug_key);
+            Ok(Box::new(console))
+        },
+        "tcp" => Ok(Box::new(StreamSerial::connect(params)?)),
+        "telnet" => {
+            let address = listen_address(params, "0.0.0.0");
+            let telnet = TelnetSerial::listen(&address)?;
+            println!("Serving the console with telnet on {}", address);
+            Ok(Box::new(telnet))
+        },
+        #[cfg(unix)]
+        "pty" => {
+            let pty = PtySerial::open()?;
+            println!("Serial port on {}", pty.slave_path());
+            Ok(Box::new(pty))
+        },
+        "file" => {
+            let mut files = params.splitn(2, ',');
+            let input = files.next().unwrap();
+            Ok(Box::new(StreamSerial::open_files(input, files.next())?))
+        },
+        _ => Err(Error::other(format!("Unknown serial device {}", spec))),
+    }
+}
+
+/// Address to listen on, on the default host if only the port is given.
+fn listen_address(address: &str, default_host: &str) -> String {
+    if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", default_host, address)
+    }
+}
+
+/// Loads an image file, or mounts a host directory.
+fn open_disk(path: &str, use_overlay: bool, write_protected: bool) -> Result<Box<dyn Disk>> {
+    if Path::new(path).is_dir() {
+        // With the overlay the host files are not changed
+        let mut directory = HostDirectory::open(path, !use_overlay)?;
+        directory.write_protected |= write_protected;
+        Ok(Box::new(directory))
+    } else {
+        let mut media = if use_overlay {
+            Media::new_from_file_with_overlay(path)?
+        } else {
+            Media::new_from_file(path)?
+        };
+        media.write_protected |= write_protected;
+        Ok(Box::new(media))
+    }
+}
+
+/// Executes a command of the control channel, returns the reply. The
+/// host directories inserted are written back only with write_back, any
+/// client could delete the host files.
+fn control_command(emulator: &mut Emulator, request: &Request,
+        names: &mut [Option<String>], use_overlay: bool, write_back: bool) -> String {
+    match &request.command {
+        Command::List => names.iter().enumerate()
+            .map(|(drive, name)| format!("{}: {}", drive, name.as_deref().unwrap_or("empty")))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Command::Eject(drive) => match emulator.eject_disk(*drive) {
+            Some(_) => format!("{}: ejected {}", drive, names[*drive].take().unwrap_or_default()),
+            None => format!("{}: the drive is empty", drive),
+        },
+        Command::Insert(drive, path) => {
+            if names[*drive].is_some() {
+                return format!("{}: the drive is not empty, eject it first", drive);
+            }
+            let use_overlay = use_overlay || (Path::new(path).is_dir() && !write_back);
+            match open_disk(path, use_overlay, false) {
+                Ok(disk) => match emulator.insert_disk(*drive, disk) {
+                    Ok(()) => {
+                        names[*drive] = Some(path.clone());
+                        format!("{}: {}", drive, path)
+                    },
+                    Err(_) => format!("{}: the drive is not empty, eject it first", drive),
+                },
+                Err(err) => format!("{}: {}", path, err),
+            }
+        },
+        Command::Save(path) => match snapshot::save(emulator, path) {
+            Ok(()) => format!("Snapshot saved to {}", path),
+            Err(err) => format!("{}: {}", path, err),
+        },
+    }
+}
+
+/// Saves a snapshot if a file is given, exits on failure.
+fn save_snapshot(emulator: &mut Emulator, path: Option<&str>) {
+    if let Some(path) = path {
+        if let Err(err) = snapshot::save(emulator, path) {
+            eprintln!("{}: {}", path, err);
+            process::exit(1);
+        }
+        println!("Snapshot saved to {}", path);
+    }
+}
+
+/// Shows the state and executes the debugger commands until one resumes
+/// the execution. Returns false to quit, also at the end of the input.
+fn debug_session(debugger: &mut Debugger, emulator: &mut Emulator) -> bool {
+    println!();
+    println!("{}", debugger.status(emulator));
+    loop {
+        print!("debug> ");
+        stdout().flush().unwrap();
+        let line = match Console::read_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => return false,
+            Err(err) => {
+                eprintln!("{}", err);
+                return false;
+            },
+        };
+        let (action, text) = debugger.command(emulator, &line);
+        if !text.is_empty() {
+            println!("{}", text);
+        }
+        match action {
+            Action::Prompt => {},
+            Action::Resume => return true,
+            Action::Quit => return false,
+        }
+    }
+}
+
+/// Prints the files of an image with the columns of CAT F=L, and the
+/// usage like STATUS.
+fn catalog(image: &str, all: bool) -> Result<()> {
+    let fs = Filesystem::new(Media::new_from_bytes(&image_format::load(&fs::read(image)?)?.1));
+    let files: Vec<_> = fs.files()?.into_iter()
+        .filter(|file| all || !file.is_secret())
+        .collect();
+    let width = files.iter().map(|file| file.name.len()).max().unwrap_or(0).max(8);
+
+    println!("{}", fs.label()?);
+    println!();
+    println!("{:width$} FILE RECORD RECORD FILE  STARTING DATE OF   DATE OF", "", width = width);
+    println!("{:width$} TYPE COUNT  LENGTH PROPS ADDRESS  CREATION  LAST MOD.", "FILENAME", width = width);
+    let mut sectors = 0;
+    for file in files.iter() {
+        let address = if file.file_type & filesystem::TYPE_PROCEDURE != 0 {
+            format!("{:04X}", file.starting_address)
+        } else {
+            String::new()
+        };
+        println!("{:width$} {:4} {:5}  {:04X}   {:5} {:4}     {:6}    {:6}",
+            file.name, file.type_name(), file.record_count, file.record_length,
+            file.properties_name(), address,
+            file.created.as_deref().unwrap_or("......"),
+            file.modified.as_deref().unwrap_or("......"),
+            width = width);
+        sectors += file.sectors();
+    }
+    println!();
+    println!("{:6} FILES LISTED", files.len());
+    println!("{:6} TOTAL SECTORS FOR LISTED FILES", sectors);
+    let used = fs.used_sectors()?;
+    println!("{:6} SECTORS USED", used);
+    println!("{:6} SECTORS AVAILABLE", TRACK_COUNT * SECTOR_COUNT - used);
+    Ok(())
+}
+
+/// Copies files of an image to a host directory, all the files if no names
+/// are given. A damaged file doesn't stop the extraction of the others,
+/// returns false if any failed.
+fn extract_files(image: &str, names: Option<Vec<&str>>, directory: &str,
+        all: bool, text: bool) -> Result<bool> {
+    let fs = Filesystem::new(Media::new_from_bytes(&image_format::load(&fs::read(image)?)?.1));
+    let files = match names {
+        Some(names) => names.iter()
+            .map(|name| fs.find(name))
+            .collect::<Result<Vec<_>>>()?,
+        None => fs.files()?.into_iter()
+            .filter(|file| all || !file.is_secret())
+            .collect(),
+    };
+
+    fs::create_dir_all(directory)?;
+    let mut success = true;
+    for file in files {
+        let mut content = match fs.read_file(&file) {
+            Ok(content) => content,
+            Err(err) => {
+                eprintln!("{}: {}", file.name, err);
+                success = false;
+                continue;
+            }
+        };
+        if text && file.is_ascii() {
+            content = filesystem::to_host_text(&content);
+        }
+        let host_name = filesystem::host_name(&file.name);
+        fs::write(Path::new(directory).join(&host_name), &content)?;
+        println!("{}: {} bytes", host_name, content.len());
+    }
+    Ok(success)
+}
+
+fn import_files(image: &str, files: Vec<&str>, name: Option<&str>, text: bool) -> Result<()> {
+    let mut fs = Filesystem::new(Media::new_from_file(image)?);
+    for file in files {
+        let name = match name {
+            Some(name) => name.to_string(),
+            // RIO names are upper case, the commands are typed in upper case
+            None => Path::new(file).file_name()
+                .map(|name| name.to_string_lossy().to_ascii_uppercase())
+                .unwrap_or_default(),
+        };
+        let mut content = fs::read(file)?;
+        let file_type = if text {
+            content = filesystem::from_host_text(&content);
+            filesystem::TYPE_ASCII
+        } else {
+            filesystem::TYPE_BINARY
+        };
+        let entry = fs.write_file(&name, file_type, &content)
+            .map_err(|err| Error::new(err.kind(), format!("{}: {}", file, err)))?;
+        println!("{}: {} bytes, {} sectors", entry.name, entry.size(), entry.sectors());
+    }
+    Ok(())
+}
+
+fn check_image(image: &str, repair: bool) -> Result<bool> {
+    let media = if repair {
+        Media::new_from_file(image)?
+    } else {
+        Media::new_from_bytes(&image_format::load(&fs::read(image)?)?.1)
+    };
+    let mut fs = Filesystem::new(media);
+    let report = check::check(&mut fs, repair)?;
+
+    for problem in report.problems.iter() {
+        println!("{}{}", problem.description, if problem.repaired {", repaired"} else {""});
+    }
+    if report.missing_crcs > 0 {
+        println!("{} sectors without CRC{}", report.missing_crcs, if repair {", written"} else {""});
+    }
+    let repaired = report.problems.iter().filter(|problem| problem.repaired).count();
+    println!("{} problems found, {} repaired", report.problems.len(), repaired);
+    Ok(report.is_clean())
+}
+
+fn format_image(image: &str, label: Option<&str>) -> Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(image)?;
+    file.write_all(&media::blank_image())?;
+    drop(file);
+
+    if let Some(label) = label {
+        Filesystem::new(Media::new_from_file(image)?).initialize(label)?;
+    }
+    Ok(())
+}
+
+/// Copies an image to a new file, in the format given or by the extension,
+/// MCZ by default.
+fn convert_image(source: &str, destination: &str, format: Option<&str>, force: bool) -> Result<()> {
+    let (source_format, content) = fs::read(source).and_then(|content| image_format::load(&content))
+        .map_err(|err| Error::new(err.kind(), format!("{}: {}", source, err)))?;
+    let format = format.and_then(ImageFormat::from_name)
+        .or_else(|| ImageFormat::from_filename(destination))
+        .unwrap_or(ImageFormat::Mcz);
+
+    // Without the pointers RIO can't follow the directory and the files
+    let rio_volume = Filesystem::new(Media::new_from_bytes(&content)).directory().is_ok();
+    if source_format.has_trailers() && !format.has_trailers() && rio_volume && !force {
+        return Err(Error::other(format!(
+            "{}: RIO volume, the pointers of the sectors would be lost in the {} image. Use --force to convert it anyway",
+            source, format.name())));
+    }
+
+    OpenOptions::new().write(true).create_new(true).open(destination)
+        .and_then(|mut file| file.write_all(&format.encode(&content)))
+        .map_err(|err| Error::new(err.kind(), format!("{}: {}", destination, err)))?;
+    println!("{}: {} image converted to {}", destination, source_format.name(), format.name());
+    if source_format.has_trailers() && !format.has_trailers() {
+        println!("The sector headers, pointers and CRCs are not kept");
+    }
+    Ok(())
 }
 
 fn main() {
     // Parse arguments
     let matches = App::new(WELCOME)
         .arg(Arg::with_name("DISK")
-            .help("Image file")
+            .help("Image file, or a host directory mounted as a RIO disk")
             .required(false)
             .multiple(true))
         .arg(Arg::with_name("cpu_trace")
@@ -62,78 +371,507 @@ fn main() {
             .short("f")
             .long("floppy-trace")
             .help("Traces disk access"))
+        .arg(Arg::with_name("floppy_ports")
+            .short("p")
+            .long("floppy-ports")
+            .help("Emulates the floppy controller ports instead of trapping the PROM floppy driver"))
+        .arg(Arg::with_name("overlay")
+            .short("o")
+            .long("overlay")
+            .help("Keeps the images unchanged, the writes go to IMAGE.ovl overlay files"))
+        .arg(Arg::with_name("write_protect")
+            .short("w")
+            .long("write-protect")
+            .value_name("DRIVE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Write protects a drive. Read-only files and the default images are always protected"))
+        .arg(Arg::with_name("break_key")
+            .short("b")
+            .long("break-key")
+            .value_name("KEY")
+            .default_value("^]")
+            .help("Host key for the BREAK button, as ^X, 0x1d or none. Generates a NMI to enter the monitor"))
+        .arg(Arg::with_name("debug_key")
+            .long("debug-key")
+            .value_name("KEY")
+            .default_value("^^")
+            .help("Host key to enter the debugger of the emulator, as ^X, 0x1e or none"))
+        .arg(Arg::with_name("debug")
+            .short("d")
+            .long("debug")
+            .conflicts_with_all(&["script", "batch", "do_file"])
+            .help("Starts in the debugger of the emulator, the commands are read from stdin"))
+        .arg(Arg::with_name("serial")
+            .short("s")
+            .long("serial")
+            .value_name("DEVICE")
+            .default_value("terminal")
+            .help("Console on the serial port: terminal, tcp:HOST:PORT, telnet:[HOST:]PORT, pty or file:INPUT[,OUTPUT]"))
+        .arg(Arg::with_name("script")
+            .long("script")
+            .value_name("FILE")
+            .help("Runs a send/expect script on the console and exits, fails on a timeout"))
+        .arg(Arg::with_name("batch")
+            .long("batch")
+            .value_name("COMMAND")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Boots RIO, runs the command and exits with 0, or with a status from the RIO ERCODE if it is an error. Can be repeated, stops on the first error"))
+        .arg(Arg::with_name("do_file")
+            .long("do")
+            .value_name("FILE")
+            .help("Boots RIO, runs the DO file and exits like --batch"))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("SECONDS")
+            .default_value("3600")
+            .help("Time limit in emulated seconds of the batch mode, exits with 124 when reached"))
+        .arg(Arg::with_name("printer")
+            .long("printer")
+            .value_name("FILE")
+            .help("Attaches a printer to the parallel ports, writes to FILE or to stdout with -"))
+        .arg(Arg::with_name("record")
+            .long("record")
+            .value_name("FILE")
+            .conflicts_with("replay")
+            .help("Records the console input with the instruction of each key, to replay the run"))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .help("Replays a recorded console input, then the console input is live"))
+        .arg(Arg::with_name("restore")
+            .long("restore")
+            .value_name("FILE")
+            .help("Resumes from a snapshot, the same images have to be on the drives"))
+        .arg(Arg::with_name("save")
+            .long("save")
+            .value_name("FILE")
+            .help("Saves a snapshot when the script or the batch commands end"))
+        .arg(Arg::with_name("control")
+            .long("control")
+            .value_name("[HOST:]PORT")
+            .help("Serves a control channel to list, eject and insert the disks while running, on 127.0.0.1 if only the port is given"))
+        .arg(Arg::with_name("control_write_back")
+            .long("control-write-back")
+            .requires("control")
+            .help("Writes back the changes of RIO to the host directories inserted with the control channel, kept in memory by default"))
+        .subcommand(SubCommand::with_name("cat")
+            .about("Lists the files of a RIO image, like CAT F=L")
+            .arg(Arg::with_name("all")
+                .short("a")
+                .long("all")
+                .help("Includes the secret files"))
+            .arg(Arg::with_name("IMAGE")
+                .required(true)))
+        .subcommand(SubCommand::with_name("extract")
+            .about("Copies files from a RIO image to the host")
+            .arg(Arg::with_name("all")
+                .short("a")
+                .long("all")
+                .help("Includes the secret files when no file is given"))
+            .arg(Arg::with_name("text")
+                .short("t")
+                .long("text")
+                .help("Converts the line ends of the ASCII files to host text"))
+            .arg(Arg::with_name("directory")
+                .short("d")
+                .long("directory")
+                .value_name("DIR")
+                .default_value(".")
+                .help("Destination directory"))
+            .arg(Arg::with_name("IMAGE")
+                .required(true))
+            .arg(Arg::with_name("FILE")
+                .help("Files to extract, all if none")
+                .multiple(true)))
+        .subcommand(SubCommand::with_name("import")
+            .about("Copies files from the host to a RIO image")
+            .arg(Arg::with_name("text")
+                .short("t")
+                .long("text")
+                .help("Writes ASCII files, converting the line ends of the host text"))
+            .arg(Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .value_name("NAME")
+                .help("Name on the image, the host file name in upper case by default"))
+            .arg(Arg::with_name("IMAGE")
+                .required(true))
+            .arg(Arg::with_name("FILE")
+                .required(true)
+                .multiple(true)))
+        .subcommand(SubCommand::with_name("check")
+            .about("Checks the consistency of a RIO image")
+            .arg(Arg::with_name("repair")
+                .short("r")
+                .long("repair")
+                .help("Repairs the problems found"))
+            .arg(Arg::with_name("IMAGE")
+                .required(true)))
+        .subcommand(SubCommand::with_name("format")
+            .about("Creates a blank image")
+            .arg(Arg::with_name("label")
+                .short("l")
+                .long("label")
+                .value_name("LABEL")
+                .help("Writes an empty RIO directory with the volume label"))
+            .arg(Arg::with_name("IMAGE")
+                .required(true)))
+        .subcommand(SubCommand::with_name("convert")
+            .about("Copies an image to a new file in another format")
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["mcz", "raw", "imd"])
+                .help("Format of the new image, by the extension or MCZ if not given"))
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Converts also RIO volumes to raw and IMD, the sector pointers are lost"))
+            .arg(Arg::with_name("SOURCE")
+                .required(true))
+            .arg(Arg::with_name("DESTINATION")
+                .required(true)))
+        .subcommand(SubCommand::with_name("commit")
+            .about("Writes the overlay files into the images")
+            .arg(Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("Writes also into raw and IMD images, the sector pointers are lost"))
+            .arg(Arg::with_name("IMAGE")
+                .required(true)
+                .multiple(true)))
+        .subcommand(SubCommand::with_name("discard")
+            .about("Deletes the overlay files of the images")
+            .arg(Arg::with_name("IMAGE")
+                .required(true)
+                .multiple(true)))
         .get_matches();
 
+    // Image commands
+    match matches.subcommand() {
+        ("cat", Some(cat)) => {
+            let image = cat.value_of("IMAGE").unwrap();
+            if let Err(err) = catalog(image, cat.is_present("all")) {
+                eprintln!("{}: {}", image, err);
+                process::exit(1);
+            }
+            return;
+        },
+        ("extract", Some(extract)) => {
+            let image = extract.value_of("IMAGE").unwrap();
+            let names = extract.values_of("FILE").map(|names| names.collect());
+            match extract_files(image, names, extract.value_of("directory").unwrap(),
+                    extract.is_present("all"), extract.is_present("text")) {
+                Ok(true) => {},
+                Ok(false) => process::exit(1),
+                Err(err) => {
+                    eprintln!("{}: {}", image, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        },
+        ("import", Some(import)) => {
+            let image = import.value_of("IMAGE").unwrap();
+            let files: Vec<&str> = import.values_of("FILE").unwrap().collect();
+            let name = import.value_of("name");
+            if name.is_some() && files.len() > 1 {
+                eprintln!("The name can only be given to one file");
+                process::exit(1);
+            }
+            if let Err(err) = import_files(image, files, name, import.is_present("text")) {
+                eprintln!("{}: {}", image, err);
+                process::exit(1);
+            }
+            return;
+        },
+        ("check", Some(check)) => {
+            let image = check.value_of("IMAGE").unwrap();
+            match check_image(image, check.is_present("repair")) {
+                Ok(true) => {},
+                Ok(false) => process::exit(1),
+                Err(err) => {
+                    eprintln!("{}: {}", image, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        },
+        ("format", Some(format)) => {
+            let image = format.value_of("IMAGE").unwrap();
+            if let Err(err) = format_image(image, format.value_of("label")) {
+                eprintln!("{}: {}", image, err);
+                process::exit(1);
+            }
+            return;
+        },
+        ("convert", Some(convert)) => {
+            if let Err(err) = convert_image(convert.value_of("SOURCE").unwrap(),
+                    convert.value_of("DESTINATION").unwrap(), convert.value_of("format"),
+                    convert.is_present("force")) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            return;
+        },
+        ("commit", Some(commit)) => {
+            for image in commit.values_of("IMAGE").unwrap() {
+                match overlay::commit(image, commit.is_present("force")) {
+                    Ok(count) => println!("{}: {} sectors written", image, count),
+                    Err(err) => {
+                        eprintln!("{}: {}", image, err);
+                        process::exit(1);
+                    }
+                }
+            }
+            return;
+        },
+        ("discard", Some(discard)) => {
+            for image in discard.values_of("IMAGE").unwrap() {
+                if let Err(err) = overlay::discard(image) {
+                    eprintln!("{}: {}", image, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        },
+        _ => {}
+    }
+
     let trace_cpu = matches.is_present("cpu_trace");
+    let use_overlay = matches.is_present("overlay");
+    let control_write_back = matches.is_present("control_write_back");
     let trace_io = matches.is_present("io_trace");
     let trace_floppy = matches.is_present("floppy_trace");
+    let floppy_trap = !matches.is_present("floppy_ports");
+    let serial = matches.value_of("serial").unwrap();
+    let break_key = if serial == "terminal" {
+        parse_key(matches.value_of("break_key").unwrap())
+    } else {
+        None
+    };
+    let debug_key = if serial == "terminal" {
+        parse_key(matches.value_of("debug_key").unwrap())
+    } else {
+        None
+    };
+    let debug = matches.is_present("debug");
+    let record = matches.value_of("record");
+    let replay = matches.value_of("replay");
+    // The telnet clients and the replays can send BREAK
+    let break_available = break_key.is_some() || serial.starts_with("telnet") || replay.is_some();
+    let script = matches.value_of("script").map(|filename| {
+        Script::load(filename).unwrap_or_else(|err| {
+            eprintln!("{}: {}", filename, err);
+            process::exit(1);
+        })
+    });
+    let restore = matches.value_of("restore");
+    let save = matches.value_of("save");
+    let mut commands: Vec<String> = Vec::new();
+    if let Some(do_file) = matches.value_of("do_file") {
+        commands.push(format!("DO {}", do_file));
+    }
+    if let Some(batch) = matches.values_of("batch") {
+        commands.extend(batch.map(|command| command.to_string()));
+    }
+    let batch = if commands.is_empty() {
+        None
+    } else {
+        let timeout = matches.value_of("timeout").unwrap();
+        let timeout = match timeout.parse::<f64>() {
+            Ok(timeout) if timeout > 0.0 => timeout,
+            _ => {
+                eprintln!("Invalid timeout {}, expected seconds like 60 or 0.5", timeout);
+                process::exit(1);
+            },
+        };
+        let mut batch = Batch::new(commands, timeout);
+        // A restored machine is already running
+        batch.set_boot(restore.is_none());
+        Some(batch)
+    };
+    let printer = matches.value_of("printer");
     let disks = matches.values_of("DISK");
 
-    // Init device
-    let mut machine = MczMachine::new(trace_io);
-    let mut cpu = Cpu::new_z80();
-    cpu.set_trace(trace_cpu);
-
     // Load disks
-    let mut drives: Vec<Media> = vec![];
+    let protected: Vec<usize> = matches.values_of("write_protect")
+        .map(|drives| drives
+            .map(|drive| drive.parse().unwrap_or_else(|_| {
+                eprintln!("Invalid drive number {}", drive);
+                process::exit(1);
+            }))
+            .collect())
+        .unwrap_or_default();
+    let mut drives: Vec<Box<dyn Disk>> = vec![];
+    let mut names: Vec<Option<String>> = vec![];
     match disks {
         Some(disks) => {
-            for disk in disks {
-                drives.push(Media::new_from_file(disk).unwrap());
+            for (drive, disk) in disks.enumerate() {
+                let media = open_disk(disk, use_overlay, protected.contains(&drive))
+                    .unwrap_or_else(|err| {
+                        eprintln!("{}: {}", disk, err);
+                        process::exit(1);
+                    });
+                drives.push(media);
+                names.push(Some(disk.to_string()));
             }
         },
         None => {
             // Load default disks if none specified
-            drives.push(Media::new_from_bytes(DISK_2_2));
-            drives.push(Media::new_from_bytes(DISK_EMPTY));
-            drives.push(Media::new_from_bytes(DISK_UTILS));
-            drives.push(Media::new_from_bytes(DISK_PLZ));
-            drives.push(Media::new_from_bytes(DISK_BASIC));
-            drives.push(Media::new_from_bytes(DISK_COBOL));
-            drives.push(Media::new_from_bytes(DISK_FORTRAN));
-            drives.push(Media::new_from_bytes(DISK_PASCAL));
+            let defaults = [
+                (DISK_2_2, "13-3001-03_MCZ-PDS_RIO_2-2.MCZ"),
+                (DISK_EMPTY, "EMPTY.MCZ"),
+                (DISK_UTILS, "13-3051-04__MCZ_PDS_RIO_UTILITIES.MCZ"),
+                (DISK_PLZ, "13-3301-03__MCZ_PDS_RIO_PLZ_V3.MCZ"),
+                (DISK_BASIC, "13-3311-03__MCZ_PDS_BASIC_V3.MCZ"),
+                (DISK_COBOL, "13-3321-03__MCZ_PDS_COBOL+RTI_V1.5.MCZ"),
+                (DISK_FORTRAN, "13-3331-03__MCZ_PDS_FORTRAN_V4.MCZ"),
+                (DISK_PASCAL, "13-3371-02__MCZ_PDS_PASCAL_V2.MCZ"),
+            ];
+            for (content, name) in defaults.iter() {
+                drives.push(Box::new(Media::new_from_bytes(content)));
+                names.push(Some(format!("{} (built in)", name)));
+            }
         }
 
     }
+    names.resize(MAX_DRIVES, None);
+
+    if let Some(&drive) = protected.iter().find(|&&drive| drive >= drives.len()) {
+        eprintln!("Drive {} to write protect not loaded", drive);
+        process::exit(1);
+    }
+
+    // Printer
+    let parallel = printer.map(|printer| {
+        let sink: Box<dyn Write> = if printer == "-" {
+            Box::new(stdout())
+        } else {
+            match File::create(printer) {
+                Ok(file) => Box::new(file),
+                Err(err) => {
+                    eprintln!("{}: {}", printer, err);
+                    process::exit(1);
+                },
+            }
+        };
+        Box::new(Printer::new(sink)) as Box<dyn ParallelDevice>
+    });
+
+    // Init device
+    // The script and the batch type on a console in memory
+    let script_console = MemorySerial::new();
+    let console = if script.is_some() || batch.is_some() {
+        Box::new(script_console.clone())
+    } else {
+        serial_device(serial, break_key, debug_key).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        })
+    };
+    let console: Box<dyn SerialDevice> = if let Some(record) = record {
+        Box::new(Recorder::create(console, record).unwrap_or_else(|err| {
+            eprintln!("{}: {}", record, err);
+            process::exit(1);
+        }))
+    } else if let Some(replay) = replay {
+        Box::new(Player::load(console, replay).unwrap_or_else(|err| {
+            eprintln!("{}: {}", replay, err);
+            process::exit(1);
+        }))
+    } else {
+        console
+    };
+    let mut emulator = Emulator::new(drives, console);
+    emulator.set_parallel(parallel);
+    emulator.set_floppy_trap(floppy_trap);
+    emulator.set_trace_cpu(trace_cpu);
+    emulator.set_trace_io(trace_io);
+    emulator.set_trace_floppy(trace_floppy);
 
     // Start the cpu
     println!("{}", WELCOME);
 
-    let done = false;
-    let mut async_address: u16 = 0;
-    let mut async_count = 0;
+    if let Some(restore) = restore {
+        if let Err(err) = snapshot::restore(&mut emulator, restore) {
+            eprintln!("{}: {}", restore, err);
+            process::exit(1);
+        }
+        println!("Resumed from {}", restore);
+    }
+
+    if let Some(script) = script {
+        if let Err(err) = script.run(&mut emulator, &script_console, &mut stdout()) {
+            eprintln!();
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        println!();
+        save_snapshot(&mut emulator, save);
+        return;
+    }
 
-    while !done {
-        let pc = cpu.registers().pc();
-        //cpu.set_trace(trace_cpu && (pc < 0x757 || pc > 0x75b));
+    if let Some(batch) = batch {
+        let ercode = batch.run(&mut emulator, &script_console, &mut stdout())
+            .unwrap_or_else(|err| {
+                eprintln!();
+                eprintln!("{}", err);
+                process::exit(if err.kind() == ErrorKind::TimedOut {124} else {1});
+            });
+        println!();
+        save_snapshot(&mut emulator, save);
+        if Batch::is_error(ercode) {
+            eprintln!("ERCODE {:02X}", ercode);
+            process::exit(Batch::exit_status(ercode));
+        }
+        return;
+    }
 
-        if async_count > 0 {
-            async_count -= 1;
-            if async_count == 0 {
-                interrupt(&mut cpu, &mut machine, async_address);
-            }
+    let control = matches.value_of("control").map(|address| {
+        // Only local clients by default, they can change the disks
+        let address = listen_address(address, "127.0.0.1");
+        let control = Control::listen(&address).unwrap_or_else(|err| {
+            eprintln!("{}: {}", address, err);
+            process::exit(1);
+        });
+        println!("Serving the control channel on {}", address);
+        control
+    });
+    let mut host_poll = 0;
+
+    let mut debugger = Debugger::new();
+    if debug {
+        debugger.stop("Started in the debugger");
+        if !debug_session(&mut debugger, &mut emulator) {
+            return;
         }
+    }
 
-        if pc == FLOPPY_HANDLER {
-            // FLOPPY REQUEST
-            if async_count > 0 {
-                panic!("Floopy request with a pending async request");
+    // Without a break key, a HALT with the interrupts disabled is final
+    let mut quit = false;
+    emulator.run_until(|emulator| {
+        host_poll += 1;
+        if host_poll == HOST_POLL_INSTRUCTIONS {
+            host_poll = 0;
+            if let Some(control) = &control {
+                while let Some(request) = control.poll() {
+                    request.reply(&control_command(emulator, &request, &mut names, use_overlay, control_write_back));
+                }
             }
-
-            let iy = cpu.registers().get16(Reg16::IY);
-            async_address = rom_floopy(&mut machine, &mut drives, iy, trace_floppy);
-            if async_address != 0 {
-                async_count = 10000;
+            if emulator.machine().debug_pressed() {
+                debugger.stop("Debugger key");
             }
-            cpu.registers().set_pc(0x0797); // Jump to the RET
         }
-
-        cpu.execute_instruction(&mut machine);
-
-        if cpu.is_halted() {
-            println!("HALT instruction that will never be interrupted");
-            break;
+        if debugger.check(emulator) && !debug_session(&mut debugger, emulator) {
+            quit = true;
+            return true;
         }
+        emulator.is_stopped() && !break_available
+    });
+    if !quit {
+        println!("HALT instruction that will never be interrupted");
     }
 }
-
@@ -1,12 +1,15 @@
-//use std::io::stdout;
-//use std::io::Write;
+use std::cell::RefCell;
+use std::io::Result;
 
 use iz80::Machine;
 
-#[cfg(windows)]
-use super::console_windows::Console;
-#[cfg(unix)]
-use super::console_unix::Console;
+use super::ctc::Ctc;
+use super::interrupts::InterruptDevice;
+use super::mdc::Mdc;
+use super::media::Disk;
+use super::pio::{ParallelDevice, Pio};
+use super::serial::SerialDevice;
+use super::snapshot::{Reader, Writer};
 
 
 /* Memory map:
@@ -20,102 +23,234 @@ static ROM: &[u8] = include_bytes!("../rom/MCZ.PROM.78089.BIN");
 pub const FLOPPY_HANDLER: u16 = 0x0780;
 pub const FLOPPY_POINTERS: u16 = 0x12b4;
 
+/// Access to the memory or the ports, monitored for the debugger.
+#[derive(Clone, Copy, Debug)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+    In(u8, u8),
+    Out(u8, u8),
+}
+
 pub struct MczMachine {
     ram: [u8; 65536],
     trace_io: bool,
-    console: Console,
-
-    //i_command: usize
+    console: Box<dyn SerialDevice>,
+    mdc: Mdc,
+    ctc: Ctc,
+    pio: Pio,
+    // Accesses since last taken, if monitored. The peeks are not mutable.
+    accesses: Option<RefCell<Vec<Access>>>,
 }
 
 impl MczMachine {
-    pub fn new(trace_io: bool) -> MczMachine {
+    pub fn new(drives: Vec<Box<dyn Disk>>, console: Box<dyn SerialDevice>) -> MczMachine {
         MczMachine {
             ram: [0; 65536],
-            trace_io,
-            console: Console::new(),
+            trace_io: false,
+            console,
+            mdc: Mdc::new(drives),
+            ctc: Ctc::new(),
+            pio: Pio::new(None),
+            accesses: None,
+        }
+    }
+
+    pub fn set_trace_io(&mut self, trace_io: bool) {
+        self.trace_io = trace_io;
+    }
+
+    /// Connects a device to the parallel ports.
+    pub fn set_parallel(&mut self, device: Option<Box<dyn ParallelDevice>>) {
+        self.pio = Pio::new(device);
+    }
+
+    pub fn mdc(&mut self) -> &mut Mdc {
+        &mut self.mdc
+    }
+
+    /// Monitors the accesses to the memory and the ports. The instruction
+    /// fetches are included, the reads of the emulator to time and decode
+    /// the instructions are not.
+    pub fn set_monitor(&mut self, monitor: bool) {
+        self.accesses = if monitor {Some(RefCell::new(Vec::new()))} else {None};
+    }
+
+    /// Returns and clears the accesses monitored.
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        match self.accesses {
+            Some(ref accesses) => accesses.replace(Vec::new()),
+            None => Vec::new(),
+        }
+    }
+
+    /// Reads the memory without monitoring the access, for the emulator
+    /// itself.
+    pub fn peek_unmonitored(&self, address: u16) -> u8 {
+        if address < ROM.len() as u16 {
+            ROM[address as usize]
+        } else {
+            self.ram[address as usize]
+        }
+    }
+
+    fn monitor(&self, access: Access) {
+        if let Some(ref accesses) = self.accesses {
+            accesses.borrow_mut().push(access);
+        }
+    }
+
+    /// Saves the RAM and the state of the devices. The console and the
+    /// parallel device are on the host, they are not saved.
+    pub(crate) fn save_state(&self, writer: &mut Writer) {
+        writer.bytes(&self.ram);
+        self.mdc.save_state(writer);
+        self.ctc.save_state(writer);
+        self.pio.save_state(writer);
+    }
 
-            //i_command: 0
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        let ram = reader.bytes(self.ram.len())?;
+        self.ram.copy_from_slice(ram);
+        self.mdc.load_state(reader)?;
+        self.ctc.load_state(reader)?;
+        self.pio.load_state(reader)
+    }
+
+    /// Advances the devices clocked by the system clock.
+    pub fn advance(&mut self, cycles: u32) {
+        let sector_pulses = self.mdc.advance(cycles);
+        self.ctc.advance(cycles);
+        self.ctc.trigger(0, sector_pulses);
+        self.pio.advance();
+        self.console.advance();
+    }
+
+    /// Interrupt daisy chain from the highest to the lowest priority. The
+    /// USART and the disk controller don't generate interrupts on the
+    /// MCZ-1, the disk is timed with the CTC channel 0.
+    fn daisy_chain(&self) -> [&dyn InterruptDevice; 2] {
+        [&self.ctc, &self.pio]
+    }
+
+    fn daisy_chain_mut(&mut self) -> [&mut dyn InterruptDevice; 2] {
+        [&mut self.ctc, &mut self.pio]
+    }
+
+    /// Returns true if a device can interrupt. The devices with lower
+    /// priority than a device under service are blocked.
+    pub fn interrupt_request(&self) -> bool {
+        for device in self.daisy_chain().iter() {
+            if device.int_pending() {
+                return true;
+            }
+            if device.int_in_service() {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Acknowledges the pending interrupt, returns the vector.
+    pub fn interrupt_ack(&mut self) -> u8 {
+        for device in self.daisy_chain_mut().iter_mut() {
+            if device.int_pending() {
+                return device.int_ack();
+            }
+            if device.int_in_service() {
+                break;
+            }
+        }
+        0xff // Nothing on the bus
+    }
+
+    /// The device with the highest priority under service decodes the RETI.
+    pub fn reti(&mut self) {
+        if let Some(device) = self.daisy_chain_mut().iter_mut().find(|d| d.int_in_service()) {
+            device.reti();
         }
     }
 
+    /// Returns true if the host break key has been pressed, the equivalent
+    /// of the BREAK button of the front panel.
+    pub fn break_pressed(&mut self) -> bool {
+        self.console.break_pressed()
+    }
+
+    /// Returns true if the host key to enter the debugger has been pressed.
+    pub fn debug_pressed(&mut self) -> bool {
+        self.console.debug_pressed()
+    }
+
     fn is_key_ready(&mut self) -> bool {
-        //self.i_command < COMMAND.len()
         self.console.status()
     }
 
     fn get_key(&mut self) -> u8 {
         self.console.read()
-        /*
-        if self.is_key_ready() {
-            self.i_command += 1;
-        }
-        COMMAND[self.i_command - 1]
-        */
     }
 
     fn put_char(&mut self, ch: u8) {
-        self.console.put(ch);
-        /*
-        print!("{}", ch as char);
-        stdout().flush().unwrap();
-        */
+        self.console.write(ch);
     }
 }
 
-//const COMMAND: &[u8] = " HELP\r".as_bytes();
-
 impl Machine for MczMachine {
     fn peek(&self, address: u16) -> u8 {
         //if address >= 0x1100 && address <= 0x1300 {
         //    print!("Access to {:04x}h\n", address);
         //}
 
-        if address < ROM.len() as u16 {
-            ROM[address as usize]
-        } else {
-            self.ram[address as usize]
-        }
+        let value = self.peek_unmonitored(address);
+        self.monitor(Access::Read(address, value));
+        value
     }
 
     fn poke(&mut self, address: u16, value: u8) {
+        self.monitor(Access::Write(address, value));
         self.ram[address as usize] = value;
         // Note: write to ROM area won't be peekable
     }
 
     fn port_out(&mut self, address: u16, value: u8) {
-        let port = address as u8 & 0b_1111_1111; // Pins used
+        let port = address as u8; // Pins used
+        self.monitor(Access::Out(port, value));
 
         if self.trace_io && port != 0xde {
             println!("OUT(0x{:02x} '{}', 0x{:02x})", port, port_name(port), value)
         }
 
         match port {
-//            0xd5 /*CLK1  */ => self.clk1 = value,
+            0xcf..=0xd3 /*MDC*/ => self.mdc.port_out(port, value),
+            0xd4..=0xd7 /*CTC*/ => self.ctc.port_out((port - 0xd4) as usize, value),
+            0xd8..=0xd9 /*PIO*/ => self.pio.data_out((port - 0xd8) as usize, value),
+            0xda..=0xdb /*PIO*/ => self.pio.control_out((port - 0xda) as usize, value),
             0xde /*SERDAT*/ => self.put_char(value),
             _ => {}
-        } 
+        }
     }
 
     fn port_in(&mut self, address: u16) -> u8 {
-        let port = address as u8 & 0b_1111_1111; // Pins used
+        let port = address as u8; // Pins used
 
         let value = match port {
+            0xcf..=0xd3 /*MDC*/ => self.mdc.port_in(port),
+            0xd4..=0xd7 /*CTC*/ => self.ctc.port_in((port - 0xd4) as usize),
+            0xd8..=0xd9 /*PIO*/ => self.pio.data_in((port - 0xd8) as usize),
             0xdd /*SWITCH*/ => 10, // Baud rate jumpers to 4800 baud
             0xde /*SERDAT*/ => self.get_key(),
             0xdf /*SERCON*/ =>
-                1 /* TXREADY */
+                (if self.console.transmit_ready() {1} else {0}) /* TXREADY */
                 | if self.is_key_ready() {2} else {0} /* RXREADY */,
             //0x05 => self.keyboard.get_key(),
 
-            0xd7 => 0xff, // CLK3, to pass hardware check
-
             _ => 0xbb,
         }; 
 
         if self.trace_io && port != 0xdf {
             println!("IN(0x{:02x} '{}') = 0x{:02x}", port, port_name(port), value)
         }
+        self.monitor(Access::In(port, value));
         value
     }
 }
@@ -124,17 +259,8 @@ fn port_name(port: u8) -> &'static str {
     match port {
         // Disk controller ports
         0xCF => "DSKDAT",
-        0xD0 => "DSKCOM/DSSTAT",
-            // bit 0 (OUT): DIRECT (increase track?)
-            // bit 1 (OUT): HDSTEP (decrease track?)
-            // bit 5 (IN): READY, disk ready
-            // bit 6 (IN): TO, track 0
-            // bit 7 (IN): CRC, crc error
+        0xD0 => "DSKCOM/DSSTAT", // See mdc.rs
         0xD1 => "DSKSEL",
-            // bits 2-1-0: disk number from 0 to 7
-            // bit 3: any disk selected (or seledt/deselect on OUT)
-            // bit 6: is disk attached
-            // bit 7: WRTPTC, is disk write protected
         0xD2 => "DSKCOM1",
         0xD3 => "DSKSEL1",
 
@@ -144,7 +270,11 @@ fn port_name(port: u8) -> &'static str {
         0xD6 => "CLK2", // Channel 2: User defined
         0xD7 => "CLK3", // Channel 3: User defined BRKPORT
 
-        // Z80-PIO ports 0xD8 to 0xDB
+        // Z80-PIO ports 0xD8 to 0xDB, see pio.rs
+        0xD8 => "PIOAD", // Port A data: printer control
+        0xD9 => "PIOBD", // Port B data: printer character
+        0xDA => "PIOAC",
+        0xDB => "PIOBC",
 
         // Other
         0xDD => "SWITCH", // Configuration jumpers, the 4 LSB are the tty speed
@@ -0,0 +1,684 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, Result};
+
+use super::media::*;
+use super::snapshot::{invalid, Reader, Writer};
+
+/*
+MDC floppy disk controller, emulated at the port level.
+
+The controller is driven by the PROM with a Z80-PIO in mode 3:
+    DSKDAT (0xCF): data byte read from or written to the disk
+    DSKCOM (0xD0): bits 0-4 are commands, bits 5-7 are status (DSSTAT)
+        bit 0 (OUT): DIRECT, step direction, set to step in (increase track)
+        bit 1 (OUT): HDSTEP, the head steps on the rising edge
+        bit 2 (OUT): RD_DAT, read circuitry enabled
+        bit 3 (OUT): WRDAT, write circuitry enabled
+        bit 4 (OUT): WRCRC, the controller writes the CRC
+        bit 5 (IN): READY, low when the selected disk is ready
+        bit 6 (IN): TO, low when the head is on track 0
+        bit 7 (IN): CRC, high on CRC error
+    DSKSEL (0xD1): bits 0-3 select the drive, bits 5-7 are status
+        bits 2-1-0: disk number from 0 to 7
+        bit 3: a disk is selected
+        bit 6 (IN): high when the disk is not attached
+        bit 7 (IN): WRTPTC, low when the disk is write protected
+    DSKCOM1 (0xD2) and DSKSEL1 (0xD3) are the PIO control ports.
+
+The drives can be empty, the disks are inserted and ejected while running.
+An empty drive is not ready and not attached. The sectors written on each
+disk are tracked for the snapshots.
+
+The disks rotate at 360 rpm. Each of the 32 sectors has a hole that
+generates a pulse when it passes under the sensor. An additional index
+hole in the middle of the last sector makes 33 pulses per revolution. The
+pulses are the clock of the CTC channel 0, the PROM counts them to find
+the requested sector.
+
+The data transfer is simplified, it has no per byte timing: DSKDAT moves
+the next byte of the sector as soon as it is read or written. The sector
+is the one under the head when RD_DAT or WRDAT rises, the transfer
+completes even if the program is slower than the disk would be.
+*/
+
+// Timing in T-states for the 2.5 MHz clock
+pub const SECTOR_TIME: u32 = 2_500_000 / 6 / SECTOR_COUNT as u32; // 360 rpm
+pub const REVOLUTION_TIME: u32 = SECTOR_TIME * SECTOR_COUNT as u32;
+const INDEX_TIME: u32 = REVOLUTION_TIME - SECTOR_TIME / 2;
+
+/// Drives of the controller, 0 to 7
+pub const MAX_DRIVES: usize = 8;
+
+const COM_DIRECT: u8 = 0x01;
+const COM_HDSTEP: u8 = 0x02;
+const COM_RD_DAT: u8 = 0x04;
+const COM_WRDAT: u8 = 0x08;
+const COM_WRCRC: u8 = 0x10;
+const COM_MASK: u8 = 0x1f;
+
+const STAT_NOT_READY: u8 = 0x20;
+const STAT_NOT_TRACK0: u8 = 0x40;
+const STAT_CRC_ERROR: u8 = 0x80;
+
+const SEL_SELECTED: u8 = 0x08;
+const SEL_MASK: u8 = 0x0f;
+const SEL_NOT_ATTACHED: u8 = 0x40;
+const SEL_WRITABLE: u8 = 0x80;
+
+enum Transfer {
+    Idle,
+    Reading {
+        data: [u8; SECTOR_SIZE_IN_FILE],
+        index: usize,
+        crc_error: bool,
+    },
+    Writing {
+        drive: usize,
+        track: usize,
+        sector: usize,
+        data: Vec<u8>,
+        crc: bool,
+    },
+}
+
+pub struct Mdc {
+    drives: Vec<Option<Box<dyn Disk>>>,
+    heads: [u8; MAX_DRIVES],
+    select: u8,
+    command: u8,
+    position: u32,
+    transfer: Transfer,
+    crc_error: bool,
+    // Sectors written on each drive, as track and sector
+    changed: Vec<BTreeSet<(usize, usize)>>,
+    trace: bool,
+}
+
+impl Mdc {
+    pub fn new(drives: Vec<Box<dyn Disk>>) -> Mdc {
+        let mut drives: Vec<_> = drives.into_iter().map(Some).collect();
+        drives.resize_with(MAX_DRIVES, || None);
+        Mdc {
+            drives,
+            heads: [0; MAX_DRIVES],
+            select: 0,
+            command: 0,
+            position: 0,
+            transfer: Transfer::Idle,
+            crc_error: false,
+            changed: vec![BTreeSet::new(); MAX_DRIVES],
+            trace: false,
+        }
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// The disk on the drive, None if the drive is empty.
+    pub fn drive(&self, drive: usize) -> Option<&dyn Disk> {
+        self.drives.get(drive)?.as_deref()
+    }
+
+    pub fn drive_mut(&mut self, drive: usize) -> Option<&mut dyn Disk> {
+        match self.drives.get_mut(drive)? {
+            Some(disk) => Some(disk.as_mut()),
+            None => None,
+        }
+    }
+
+    /// Inserts a disk on an empty drive. Returns the disk back if the
+    /// drive is not empty or doesn't exist.
+    pub fn insert(&mut self, drive: usize, disk: Box<dyn Disk>) -> std::result::Result<(), Box<dyn Disk>> {
+        match self.drives.get_mut(drive) {
+            Some(slot @ None) => {
+                *slot = Some(disk);
+                Ok(())
+            },
+            _ => Err(disk),
+        }
+    }
+
+    /// Removes the disk of a drive. A write in progress is lost.
+    pub fn eject(&mut self, drive: usize) -> Option<Box<dyn Disk>> {
+        if let Transfer::Writing{drive: writing, ..} = self.transfer {
+            if writing == drive {
+                self.transfer = Transfer::Idle;
+            }
+        }
+        if let Some(changed) = self.changed.get_mut(drive) {
+            changed.clear();
+        }
+        self.drives.get_mut(drive)?.take()
+    }
+
+    /// Writes a sector of the disk on the drive.
+    pub fn write_sector(&mut self, drive: usize, track: usize, sector: usize, data: &[u8]) -> Result<()> {
+        let disk = self.drive_mut(drive)
+            .ok_or_else(|| Error::other(format!("Drive {} is empty", drive)))?;
+        disk.write_sector(track, sector, data)?;
+        self.changed[drive].insert((track, sector));
+        Ok(())
+    }
+
+    /// Saves the state of the controller and the sectors written on each
+    /// drive.
+    pub(crate) fn save_state(&self, writer: &mut Writer) {
+        writer.bytes(&self.heads);
+        writer.u8(self.select);
+        writer.u8(self.command);
+        writer.u32(self.position);
+        writer.bool(self.crc_error);
+        match &self.transfer {
+            Transfer::Idle => writer.u8(0),
+            Transfer::Reading{data, index, crc_error} => {
+                writer.u8(1);
+                writer.bytes(data);
+                writer.u16(*index as u16);
+                writer.bool(*crc_error);
+            },
+            Transfer::Writing{drive, track, sector, data, crc} => {
+                writer.u8(2);
+                writer.u8(*drive as u8);
+                writer.u8(*track as u8);
+                writer.u8(*sector as u8);
+                writer.u16(data.len() as u16);
+                writer.bytes(data);
+                writer.bool(*crc);
+            },
+        }
+
+        for (drive, changed) in self.changed.iter().enumerate() {
+            // The changes are cleared on eject, an empty drive has none
+            let disk = match self.drive(drive) {
+                Some(disk) => disk,
+                None => {
+                    writer.bool(false);
+                    continue;
+                },
+            };
+            writer.bool(true);
+            writer.u64(fingerprint(disk, changed));
+            writer.u16(changed.len() as u16);
+            for &(track, sector) in changed.iter() {
+                writer.u8(track as u8);
+                writer.u8(sector as u8);
+                writer.bytes(disk.read_sector(track, sector));
+            }
+        }
+    }
+
+    /// Restores the state of the controller. The sectors written that
+    /// differ from the disks are written again.
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        self.heads.copy_from_slice(reader.bytes(MAX_DRIVES)?);
+        self.select = reader.u8()?;
+        self.command = reader.u8()?;
+        self.position = reader.u32()?;
+        self.crc_error = reader.bool()?;
+        self.transfer = match reader.u8()? {
+            0 => Transfer::Idle,
+            1 => {
+                let mut data = [0; SECTOR_SIZE_IN_FILE];
+                data.copy_from_slice(reader.bytes(SECTOR_SIZE_IN_FILE)?);
+                Transfer::Reading {
+                    data,
+                    index: reader.u16()? as usize,
+                    crc_error: reader.bool()?,
+                }
+            },
+            2 => {
+                let drive = reader.u8()? as usize;
+                let track = reader.u8()? as usize;
+                let sector = reader.u8()? as usize;
+                let length = reader.u16()? as usize;
+                Transfer::Writing {
+                    drive,
+                    track,
+                    sector,
+                    data: reader.bytes(length)?.to_vec(),
+                    crc: reader.bool()?,
+                }
+            },
+            kind => return Err(invalid(format!("Invalid snapshot, disk transfer {}", kind))),
+        };
+
+        // The drives are checked before writing any sector
+        let mut drives = Vec::new();
+        for drive in 0..MAX_DRIVES {
+            if !reader.bool()? {
+                if self.drive(drive).is_some() {
+                    return Err(Error::other(format!("Drive {} was empty when the snapshot was saved", drive)));
+                }
+                drives.push(BTreeMap::new());
+                continue;
+            }
+            let saved_fingerprint = reader.u64()?;
+            let count = reader.u16()?;
+            let mut sectors = BTreeMap::new();
+            for _ in 0..count {
+                let track = reader.u8()? as usize;
+                let sector = reader.u8()? as usize;
+                let data = reader.bytes(SECTOR_SIZE_IN_FILE)?;
+                if track >= TRACK_COUNT || sector >= SECTOR_COUNT {
+                    return Err(invalid(format!("Invalid snapshot, sector {}/{}", track, sector)));
+                }
+                sectors.insert((track, sector), data);
+            }
+            let disk = self.drive(drive).ok_or_else(|| Error::other(
+                format!("Drive {} is empty, the snapshot has a disk on it", drive)))?;
+            let changed = sectors.keys().copied().collect();
+            if fingerprint(disk, &changed) != saved_fingerprint {
+                return Err(Error::other(format!("Drive {}: not the disk of the snapshot", drive)));
+            }
+            let differ = sectors.iter().any(|(&(track, sector), &data)| disk.read_sector(track, sector) != data);
+            if differ && disk.is_write_protected() {
+                return Err(Error::other(format!("Drive {}: Disk is write protected", drive)));
+            }
+            drives.push(sectors);
+        }
+
+        for (drive, sectors) in drives.into_iter().enumerate() {
+            self.changed[drive].clear();
+            for ((track, sector), data) in sectors {
+                let disk = self.drive(drive).unwrap();
+                if disk.read_sector(track, sector) != data {
+                    self.write_sector(drive, track, sector, data).map_err(|err|
+                        Error::new(err.kind(), format!("Drive {}: {}", drive, err)))?;
+                }
+                self.changed[drive].insert((track, sector));
+            }
+        }
+        Ok(())
+    }
+
+    /// Advances the disk rotation. Returns the number of sector and
+    /// index pulses in the elapsed time.
+    pub fn advance(&mut self, cycles: u32) -> u32 {
+        let start = self.position as u64;
+        let end = start + cycles as u64;
+        let revolution = REVOLUTION_TIME as u64;
+        let index = INDEX_TIME as u64;
+
+        let sector_pulses = end / SECTOR_TIME as u64 - start / SECTOR_TIME as u64;
+        let index_pulses = (end + revolution - index) / revolution
+            - (start + revolution - index) / revolution;
+
+        self.position = (end % revolution) as u32;
+        (sector_pulses + index_pulses) as u32
+    }
+
+    fn selected(&self) -> Option<usize> {
+        if self.select & SEL_SELECTED != 0 {
+            let drive = (self.select & 0x07) as usize;
+            if self.drives[drive].is_some() {
+                return Some(drive);
+            }
+        }
+        None
+    }
+
+    fn selected_disk(&self) -> Option<(usize, &dyn Disk)> {
+        let drive = self.selected()?;
+        Some((drive, self.drive(drive)?))
+    }
+
+    fn sector_under_head(&self) -> Option<usize> {
+        if self.position >= INDEX_TIME {
+            // The index hole resets the data separator, no sector in sync
+            None
+        } else {
+            Some((self.position / SECTOR_TIME) as usize)
+        }
+    }
+
+    pub fn port_in(&mut self, port: u8) -> u8 {
+        match port {
+            0xcf /*DSKDAT*/ => self.read_data(),
+            0xd0 /*DSSTAT*/ => {
+                let mut value = self.command;
+                match self.selected() {
+                    Some(drive) => {
+                        if self.heads[drive] != 0 {
+                            value |= STAT_NOT_TRACK0;
+                        }
+                    },
+                    None => value |= STAT_NOT_READY | STAT_NOT_TRACK0,
+                }
+                if self.crc_error {
+                    value |= STAT_CRC_ERROR;
+                }
+                value
+            },
+            0xd1 /*DSKSEL*/ => {
+                let attached = if self.select & SEL_SELECTED != 0 {
+                    self.selected().is_some()
+                } else {
+                    self.drives.iter().any(Option::is_some)
+                };
+                let write_protected = self.selected_disk()
+                    .is_some_and(|(_, disk)| disk.is_write_protected());
+                let mut value = self.select;
+                if !attached {
+                    value |= SEL_NOT_ATTACHED;
+                }
+                if !write_protected {
+                    value |= SEL_WRITABLE;
+                }
+                value
+            },
+            _ => 0xff,
+        }
+    }
+
+    pub fn port_out(&mut self, port: u8, value: u8) {
+        match port {
+            0xcf /*DSKDAT*/ => self.write_data(value),
+            0xd0 /*DSKCOM*/ => self.command(value & COM_MASK),
+            0xd1 /*DSKSEL*/ => {
+                self.end_transfer();
+                self.select = value & SEL_MASK;
+            },
+            _ => {} // PIO control words, the mode is fixed
+        }
+    }
+
+    fn command(&mut self, value: u8) {
+        let rising = value & !self.command;
+        let falling = self.command & !value;
+        self.command = value;
+
+        if rising & COM_HDSTEP != 0 {
+            if let Some(drive) = self.selected() {
+                let head = &mut self.heads[drive];
+                if value & COM_DIRECT != 0 {
+                    if (*head as usize) < TRACK_COUNT - 1 {
+                        *head += 1;
+                    }
+                } else if *head > 0 {
+                    *head -= 1;
+                }
+            }
+        }
+
+        if falling & (COM_RD_DAT | COM_WRDAT) != 0 {
+            self.end_transfer();
+        }
+        if rising & COM_RD_DAT != 0 {
+            self.start_read();
+        }
+        if rising & COM_WRDAT != 0 {
+            self.start_write();
+        }
+        if rising & COM_WRCRC != 0 {
+            if let Transfer::Writing{ref mut crc, ..} = self.transfer {
+                // The controller generates the CRC, the next bytes are ignored
+                *crc = true;
+            }
+        }
+    }
+
+    fn start_read(&mut self) {
+        let mut data = [0; SECTOR_SIZE_IN_FILE];
+        let mut bad_crc = false;
+        if let (Some((drive, disk)), Some(sector)) = (self.selected_disk(), self.sector_under_head()) {
+            let track = self.heads[drive] as usize;
+            data.copy_from_slice(disk.read_sector(track, sector));
+            bad_crc = crc_error(&data, track, sector);
+            if self.trace {
+                println!("MDC: read drive={} track={} sector={}", drive, track, sector);
+            }
+        }
+        self.crc_error = false;
+        self.transfer = Transfer::Reading {
+            data,
+            index: 0,
+            crc_error: bad_crc,
+        };
+    }
+
+    fn read_data(&mut self) -> u8 {
+        match self.transfer {
+            Transfer::Reading{ref data, ref mut index, crc_error} => {
+                let value = data.get(*index).copied().unwrap_or(0);
+                *index += 1;
+                if *index == SECTOR_SIZE_IN_FILE {
+                    // The CRC has passed under the head
+                    self.crc_error = crc_error;
+                }
+                value
+            },
+            _ => 0,
+        }
+    }
+
+    fn start_write(&mut self) {
+        self.transfer = match (self.selected_disk(), self.sector_under_head()) {
+            // The write circuitry is disabled on protected disks
+            (Some((drive, disk)), Some(sector)) if !disk.is_write_protected() => Transfer::Writing {
+                drive,
+                track: self.heads[drive] as usize,
+                sector,
+                data: Vec::with_capacity(SECTOR_SIZE_IN_FILE),
+                crc: false,
+            },
+            _ => Transfer::Idle,
+        };
+    }
+
+    fn write_data(&mut self, value: u8) {
+        if let Transfer::Writing{ref mut data, crc: false, ..} = self.transfer {
+            if data.len() < SECTOR_SIZE_IN_FILE - 2 {
+                data.push(value);
+            }
+        }
+    }
+
+    fn end_transfer(&mut self) {
+        let transfer = std::mem::replace(&mut self.transfer, Transfer::Idle);
+        if let Transfer::Writing{drive, track, sector, mut data, crc} = transfer {
+            if !crc || data.len() < SECTOR_SIZE_IN_FILE - 2 {
+                // The write was aborted before completing the sector
+                return;
+            }
+            data.resize(SECTOR_SIZE_IN_FILE, 0);
+            set_sector_crc(&mut data);
+            if self.trace {
+                println!("MDC: write drive={} track={} sector={}", drive, track, sector);
+            }
+            if let Err(err) = self.write_sector(drive, track, sector, &data) {
+                // The PROM sees a CRC error, like a bad write on a real disk
+                eprintln!("Drive {}: {}", drive, err);
+                self.crc_error = true;
+            }
+        }
+    }
+}
+
+/// FNV-1a hash of the sectors of a disk not written since the emulator
+/// started, they have to be the same to restore a snapshot.
+fn fingerprint(disk: &dyn Disk, changed: &BTreeSet<(usize, usize)>) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for track in 0..TRACK_COUNT {
+        for sector in 0..SECTOR_COUNT {
+            if changed.contains(&(track, sector)) {
+                continue;
+            }
+            for &byte in disk.read_sector(track, sector) {
+                hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_media() -> Box<Media> {
+        let mut content = vec![0; TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE];
+        for (index, sector) in content.chunks_mut(SECTOR_SIZE_IN_FILE).enumerate() {
+            sector[0] = 0x80 | (index % SECTOR_COUNT) as u8;
+            sector[1] = (index / SECTOR_COUNT) as u8;
+            sector[2] = index as u8;
+            set_sector_crc(sector);
+        }
+        let mut media = Media::new_from_bytes(&content);
+        media.write_protected = false;
+        Box::new(media)
+    }
+
+    fn seek(mdc: &mut Mdc, track: usize, sector: usize) {
+        for _ in 0..track {
+            mdc.port_out(0xd0, COM_DIRECT);
+            mdc.port_out(0xd0, COM_DIRECT | COM_HDSTEP);
+        }
+        mdc.port_out(0xd0, 0);
+        mdc.advance(REVOLUTION_TIME - mdc.position + sector as u32 * SECTOR_TIME);
+    }
+
+    #[test]
+    fn read_the_sector_under_the_head() {
+        let mut mdc = Mdc::new(vec![test_media()]);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        assert_eq!(mdc.port_in(0xd0) & (STAT_NOT_READY | STAT_NOT_TRACK0), 0);
+        seek(&mut mdc, 3, 5);
+        assert_eq!(mdc.port_in(0xd0) & STAT_NOT_TRACK0, STAT_NOT_TRACK0);
+
+        mdc.port_out(0xd0, COM_RD_DAT);
+        let data: Vec<u8> = (0..3).map(|_| mdc.port_in(0xcf)).collect();
+        assert_eq!(data, [0x85, 3, (3 * SECTOR_COUNT + 5) as u8]);
+    }
+
+    #[test]
+    fn write_generates_the_crc() {
+        let mut mdc = Mdc::new(vec![test_media()]);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        seek(&mut mdc, 1, 2);
+
+        mdc.port_out(0xd0, COM_WRDAT);
+        for i in 0..SECTOR_SIZE_IN_FILE - 2 {
+            mdc.port_out(0xcf, i as u8);
+        }
+        mdc.port_out(0xd0, COM_WRDAT | COM_WRCRC);
+        mdc.port_out(0xcf, 0xff);
+        mdc.port_out(0xd0, 0);
+
+        let sector = mdc.drive(0).unwrap().read_sector(1, 2);
+        assert_eq!(sector[10], 10);
+        assert_eq!(sector[CRC_OFFSET - 1], (CRC_OFFSET - 1) as u8);
+        assert_eq!(sector[CRC_OFFSET..], sector_crc(sector).to_be_bytes());
+    }
+
+    #[test]
+    fn read_reports_the_crc_error_after_the_crc() {
+        let mut media = test_media();
+        let mut data = media.read_sector(2, 7).to_vec();
+        data[20] ^= 0xff;
+        media.content[(2 * SECTOR_COUNT + 7) * SECTOR_SIZE_IN_FILE..][..SECTOR_SIZE_IN_FILE]
+            .copy_from_slice(&data);
+        let mut mdc = Mdc::new(vec![media]);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        seek(&mut mdc, 2, 7);
+
+        mdc.port_out(0xd0, COM_RD_DAT);
+        for _ in 0..SECTOR_SIZE_IN_FILE - 1 {
+            mdc.port_in(0xcf);
+        }
+        assert_eq!(mdc.port_in(0xd0) & STAT_CRC_ERROR, 0);
+        mdc.port_in(0xcf);
+        assert_eq!(mdc.port_in(0xd0) & STAT_CRC_ERROR, STAT_CRC_ERROR);
+
+        // The next sector is right
+        mdc.port_out(0xd0, 0);
+        mdc.advance(SECTOR_TIME);
+        mdc.port_out(0xd0, COM_RD_DAT);
+        assert_eq!(mdc.port_in(0xd0) & STAT_CRC_ERROR, 0);
+        for _ in 0..SECTOR_SIZE_IN_FILE {
+            mdc.port_in(0xcf);
+        }
+        assert_eq!(mdc.port_in(0xd0) & STAT_CRC_ERROR, 0);
+    }
+
+    #[test]
+    fn failed_write_is_a_crc_error() {
+        struct FailingDisk(Box<Media>);
+
+        impl Disk for FailingDisk {
+            fn read_sector(&self, track: usize, sector: usize) -> &[u8] {
+                self.0.read_sector(track, sector)
+            }
+            fn write_sector(&mut self, _track: usize, _sector: usize, _data: &[u8]) -> Result<()> {
+                Err(Error::other("Disk full"))
+            }
+            fn is_write_protected(&self) -> bool {
+                false
+            }
+        }
+
+        let mut mdc = Mdc::new(vec![Box::new(FailingDisk(test_media()))]);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        seek(&mut mdc, 1, 2);
+        mdc.port_out(0xd0, COM_WRDAT);
+        for _ in 0..SECTOR_SIZE_IN_FILE - 2 {
+            mdc.port_out(0xcf, 0x55);
+        }
+        mdc.port_out(0xd0, COM_WRDAT | COM_WRCRC);
+        assert_eq!(mdc.port_in(0xd0) & STAT_CRC_ERROR, 0);
+        mdc.port_out(0xd0, 0);
+        assert_eq!(mdc.port_in(0xd0) & STAT_CRC_ERROR, STAT_CRC_ERROR);
+    }
+
+    #[test]
+    fn aborted_write_changes_nothing() {
+        let mut mdc = Mdc::new(vec![test_media()]);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        seek(&mut mdc, 0, 0);
+        let previous = mdc.drive(0).unwrap().read_sector(0, 0).to_vec();
+
+        mdc.port_out(0xd0, COM_WRDAT);
+        for _ in 0..10 {
+            mdc.port_out(0xcf, 0x55);
+        }
+        mdc.port_out(0xd0, 0);
+        assert_eq!(mdc.drive(0).unwrap().read_sector(0, 0), &previous[..]);
+    }
+
+    #[test]
+    fn write_protected_drive_ignores_writes() {
+        let mut media = test_media();
+        media.write_protected = true;
+        let mut mdc = Mdc::new(vec![media]);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        assert_eq!(mdc.port_in(0xd1) & SEL_WRITABLE, 0);
+        seek(&mut mdc, 0, 0);
+        let previous = mdc.drive(0).unwrap().read_sector(0, 0).to_vec();
+
+        mdc.port_out(0xd0, COM_WRDAT);
+        for _ in 0..SECTOR_SIZE_IN_FILE - 2 {
+            mdc.port_out(0xcf, 0x55);
+        }
+        mdc.port_out(0xd0, COM_WRDAT | COM_WRCRC);
+        mdc.port_out(0xd0, 0);
+        assert_eq!(mdc.drive(0).unwrap().read_sector(0, 0), &previous[..]);
+    }
+
+    #[test]
+    fn missing_drive_is_not_ready() {
+        let mut mdc = Mdc::new(vec![test_media()]);
+        mdc.port_out(0xd1, SEL_SELECTED | 1);
+        assert_eq!(mdc.port_in(0xd0) & STAT_NOT_READY, STAT_NOT_READY);
+        assert_eq!(mdc.port_in(0xd1) & SEL_NOT_ATTACHED, SEL_NOT_ATTACHED);
+        mdc.port_out(0xd1, SEL_SELECTED);
+        assert_eq!(mdc.port_in(0xd1) & SEL_NOT_ATTACHED, 0);
+    }
+
+    #[test]
+    fn pulses_per_revolution() {
+        let mut mdc = Mdc::new(vec![test_media()]);
+        assert_eq!(mdc.advance(REVOLUTION_TIME), SECTOR_COUNT as u32 + 1);
+        assert_eq!(mdc.advance(SECTOR_TIME / 4), 0);
+        assert_eq!(mdc.advance(10 * REVOLUTION_TIME), 10 * (SECTOR_COUNT as u32 + 1));
+    }
+}
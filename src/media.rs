@@ -1,6 +1,9 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write, Seek, SeekFrom, Result, Error, ErrorKind};
 
+use super::image_format;
+use super::overlay::Overlay;
+
 /*
 
 See: https://rio.early8bitz.de/rio/rio-fs-sector.htm
@@ -18,16 +21,31 @@ RIO disk format:
  - 2 bytes for the sector and track of the following data in the file
  - 2 bytes for the CRC
 
+The CRC is CRC-16, polynomial 0x8005 with 0 as initial value, of the
+preceding 134 bytes. It is stored most significant byte first. Some images
+have zeros instead.
+
 */
 
 pub const SECTOR_SIZE: usize = 128;
 pub const SECTOR_SIZE_IN_FILE: usize = SECTOR_SIZE + 8;
 pub const SECTOR_COUNT: usize = 32;
-const TRACK_COUNT: usize = 77;
+pub const TRACK_COUNT: usize = 77;
+pub const CRC_OFFSET: usize = SECTOR_SIZE_IN_FILE - 2;
+
+/// Storage of the sectors of a disk drive.
+pub trait Disk {
+    /// Returns the 136 bytes of the sector, empty if out of range
+    fn read_sector(&self, track: usize, sector: usize) -> &[u8];
+    fn write_sector(&mut self, track: usize, sector: usize, data: &[u8]) -> Result<()>;
+    fn is_write_protected(&self) -> bool;
+}
 
 pub struct Media {
     pub file: Option<File>,
     pub content: Vec<u8>,
+    pub write_protected: bool,
+    overlay: Option<Overlay>,
 }
 
 impl Media {
@@ -35,6 +53,8 @@ impl Media {
         Media {
             file: None,
             content: content.to_vec(),
+            write_protected: true,
+            overlay: None,
         }
     }
 
@@ -63,29 +83,49 @@ impl Media {
         // Load content
         let mut content = Vec::new();
         file.read_to_end(&mut content)?;
+        let (format, content) = image_format::load(&content)?;
 
-        // Store the file descriptor for writable files
+        // Store the file descriptor for writable files. Only the MCZ images
+        // are written, the others don't keep the pointers.
+        if !readonly && !format.has_trailers() {
+            eprintln!("{}: {} image loaded write protected, use --overlay to write on it or convert it to MCZ",
+                filename, format.name());
+        }
+        let readonly = readonly || !format.has_trailers();
         let file = if readonly {
             None
         } else {
             Some(file)
         };
 
-        if content.len() != TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE {
-            return Err(Error::new(ErrorKind::Other, format!("Unrecognized disk image format (len {})", content.len())));
-        }
+        Ok(Media {
+            file,
+            content,
+            write_protected: readonly,
+            overlay: None,
+        })
+    }
+
+    /// Opens the image read-only, the writes go to the overlay file.
+    pub fn new_from_file_with_overlay(filename: &str) -> Result<Media> {
+        let (_, mut content) = image_format::load(&fs::read(filename)?)?;
+        let overlay = Overlay::open(filename, &mut content)?;
 
         Ok(Media {
-            file: file,
-            content: content,
+            file: None,
+            content,
+            write_protected: false,
+            overlay: Some(overlay),
         })
     }
+}
 
-    pub fn read_sector(&self, track: usize, sector: usize) -> &[u8] {
+impl Disk for Media {
+    fn read_sector(&self, track: usize, sector: usize) -> &[u8] {
         if track >= TRACK_COUNT || sector >= SECTOR_COUNT {
             return &[];
         }
-        let start = (track as usize * SECTOR_COUNT + sector as usize) * SECTOR_SIZE_IN_FILE;
+        let start = (track * SECTOR_COUNT + sector) * SECTOR_SIZE_IN_FILE;
 
         // Asserts:
         /*
@@ -102,19 +142,130 @@ impl Media {
         self.content.get(start..start + SECTOR_SIZE_IN_FILE).unwrap()
     }
 
-    pub fn write_sector(&mut self, track: usize, sector: usize, data: &[u8]) -> Result<()> {
+    fn write_sector(&mut self, track: usize, sector: usize, data: &[u8]) -> Result<()> {
         if track >= TRACK_COUNT || sector >= SECTOR_COUNT {
-            return Err(Error::new(ErrorKind::Other, format!("Invalid track/sector {}/{}", track, sector)));
+            return Err(Error::other(format!("Invalid track/sector {}/{}", track, sector)));
+        }
+        if self.write_protected {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Disk is write protected"));
         }
-        let start = (track as usize * SECTOR_COUNT + sector as usize) * SECTOR_SIZE_IN_FILE;
+        let start = (track * SECTOR_COUNT + sector) * SECTOR_SIZE_IN_FILE;
         let data_in_file = &mut self.content[start..start + SECTOR_SIZE_IN_FILE];
         data_in_file.copy_from_slice(data);
 
-        if let Some(ref mut file) = self.file {
+        if let Some(ref mut overlay) = self.overlay {
+            overlay.write_sector(track, sector, data)?;
+        } else if let Some(ref mut file) = self.file {
             file.seek(SeekFrom::Start(start as u64))?;
             file.write_all(&self.content[start..start + SECTOR_SIZE_IN_FILE])?;
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+}
+
+/// Content of a blank image, the sectors have the headers, zeros and the
+/// CRC.
+pub fn blank_image() -> Vec<u8> {
+    let mut content = vec![0; TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE];
+    for (i, sector) in content.chunks_mut(SECTOR_SIZE_IN_FILE).enumerate() {
+        sector[0] = 0x80 | (i % SECTOR_COUNT) as u8;
+        sector[1] = (i / SECTOR_COUNT) as u8;
+        set_sector_crc(sector);
+    }
+    content
+}
+
+/// CRC of the 136 bytes of a sector, the CRC bytes excluded.
+pub fn sector_crc(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data[..CRC_OFFSET].iter() {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Stores the CRC of the sector on its last 2 bytes.
+pub fn set_sector_crc(data: &mut [u8]) {
+    let crc = sector_crc(data);
+    data[CRC_OFFSET..SECTOR_SIZE_IN_FILE].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// True if the sector read from the track and sector given has a wrong
+/// CRC. The CRC is not verified on the images without CRCs, with zeros, and
+/// on the sectors with a wrong header. The headers and CRCs of those were
+/// damaged by the imaging or written by previous versions of the emulator,
+/// the data is right.
+pub fn crc_error(data: &[u8], track: usize, sector: usize) -> bool {
+    let stored = u16::from_be_bytes([data[CRC_OFFSET], data[CRC_OFFSET + 1]]);
+    data[..2] == [0x80 | sector as u8, track as u8] && stored != 0 && stored != sector_crc(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Imaged with the CRCs written by the MCZ controller
+    static RIO_206: &[u8] = include_bytes!("../disks/13-3001-01_MCZ1-20_RIO_206.MCZ");
+
+    #[test]
+    fn sector_crc_matches_the_controller() {
+        let sector = &RIO_206[..SECTOR_SIZE_IN_FILE];
+        assert_eq!(sector_crc(sector), 0xe1b6);
+        assert_eq!(sector[CRC_OFFSET..], [0xe1, 0xb6]);
+    }
+
+    #[test]
+    fn set_sector_crc_is_verified() {
+        let mut sector = RIO_206[..SECTOR_SIZE_IN_FILE].to_vec();
+        sector[10] ^= 0xff;
+        assert!(crc_error(&sector, 0, 0));
+        set_sector_crc(&mut sector);
+        assert!(!crc_error(&sector, 0, 0));
+    }
+
+    #[test]
+    fn crc_not_verified_without_crc_or_with_wrong_header() {
+        let mut sector = RIO_206[..SECTOR_SIZE_IN_FILE].to_vec();
+        sector[10] ^= 0xff;
+        assert!(!crc_error(&sector, 0, 1));
+        sector[CRC_OFFSET..].copy_from_slice(&[0, 0]);
+        assert!(!crc_error(&sector, 0, 0));
+    }
+
+    #[test]
+    fn blank_image_has_headers_and_crcs() {
+        let content = blank_image();
+        assert_eq!(content.len(), TRACK_COUNT * SECTOR_COUNT * SECTOR_SIZE_IN_FILE);
+        let media = Media::new_from_bytes(&content);
+        for track in 0..TRACK_COUNT {
+            for sector in 0..SECTOR_COUNT {
+                let data = media.read_sector(track, sector);
+                assert_eq!(data[..2], [0x80 | sector as u8, track as u8]);
+                assert_eq!(sector_crc(data), u16::from_be_bytes([data[CRC_OFFSET], data[CRC_OFFSET + 1]]));
+            }
+        }
+    }
+
+    #[test]
+    fn write_protected_media_refuses_writes() {
+        let mut media = Media::new_from_bytes(&blank_image());
+        let sector = media.read_sector(1, 2).to_vec();
+        let err = media.write_sector(1, 2, &sector).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        media.write_protected = false;
+        assert!(media.write_sector(TRACK_COUNT, 0, &sector).is_err());
+        assert!(media.write_sector(1, 2, &sector).is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write, Result, Error};
+use std::path::Path;
+
+use super::image_format;
+use super::media::*;
+
+/*
+Copy-on-write overlay of a disk image. The sectors written are appended to
+a sidecar file, "image.ovl", and the base image is never modified. Each
+record has:
+ - 1 byte for the track
+ - 1 byte for the sector
+ - 136 bytes for the sector as stored in the image
+
+The records are applied in order on load, the last write of a sector wins.
+The file is compacted on load, with only the last write of each sector.
+*/
+
+const RECORD_SIZE: usize = 2 + SECTOR_SIZE_IN_FILE;
+
+pub fn overlay_filename(image: &str) -> String {
+    format!("{}.ovl", image)
+}
+
+pub struct Overlay {
+    filename: String,
+    file: Option<File>,
+}
+
+impl Overlay {
+    /// Applies the overlay of an image, if it exists, to the content of
+    /// the image. The overlay file is created on the first write.
+    pub fn open(image: &str, content: &mut [u8]) -> Result<Overlay> {
+        let filename = overlay_filename(image);
+        if Path::new(&filename).exists() {
+            let records = fs::read(&filename)?;
+            let mut sectors = BTreeMap::new();
+            for (track, sector, data) in parse_records(&records) {
+                let start = (track * SECTOR_COUNT + sector) * SECTOR_SIZE_IN_FILE;
+                content[start..start + SECTOR_SIZE_IN_FILE].copy_from_slice(data);
+                sectors.insert((track, sector), data);
+            }
+
+            // The file only grows while running, it is compacted to the
+            // last write of each sector.
+            if sectors.len() * RECORD_SIZE != records.len() {
+                let mut compacted = Vec::with_capacity(sectors.len() * RECORD_SIZE);
+                for ((track, sector), data) in sectors {
+                    compacted.extend_from_slice(&record(track, sector, data));
+                }
+                let temporary = format!("{}.tmp", filename);
+                fs::write(&temporary, compacted)?;
+                fs::rename(&temporary, &filename)?;
+            }
+        }
+
+        Ok(Overlay {
+            filename,
+            file: None,
+        })
+    }
+
+    pub fn write_sector(&mut self, track: usize, sector: usize, data: &[u8]) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.filename)?);
+        }
+
+        self.file.as_mut().unwrap().write_all(&record(track, sector, data))
+    }
+}
+
+fn record(track: usize, sector: usize, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_SIZE);
+    record.push(track as u8);
+    record.push(sector as u8);
+    record.extend_from_slice(data);
+    record
+}
+
+/// Records of the overlay, an incomplete or invalid record ends the list.
+fn parse_records(records: &[u8]) -> impl Iterator<Item = (usize, usize, &[u8])> {
+    records.chunks_exact(RECORD_SIZE)
+        .map(|record| (record[0] as usize, record[1] as usize, &record[2..]))
+        .take_while(|&(track, sector, _)| track < TRACK_COUNT && sector < SECTOR_COUNT)
+}
+
+/// Writes the overlay sectors into the base image and removes the overlay.
+/// Returns the number of sectors written. The raw and IMD images lose the
+/// headers, pointers and CRCs of the sectors, they are written only with
+/// force.
+pub fn commit(image: &str, force: bool) -> Result<usize> {
+    let records = fs::read(overlay_filename(image))?;
+    let (format, mut content) = image_format::load(&fs::read(image)?)?;
+    if !format.has_trailers() && !force {
+        return Err(Error::other(format!(
+            "{} image, the pointers of the sectors would be lost. Convert it to MCZ or use --force",
+            format.name())));
+    }
+
+    let mut count = 0;
+    for (track, sector, data) in parse_records(&records) {
+        let start = (track * SECTOR_COUNT + sector) * SECTOR_SIZE_IN_FILE;
+        content[start..start + SECTOR_SIZE_IN_FILE].copy_from_slice(data);
+        count += 1;
+    }
+
+    // The image is written again in its format
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(image)?;
+    file.write_all(&format.encode(&content))?;
+    file.sync_all()?;
+
+    fs::remove_file(overlay_filename(image))?;
+    Ok(count)
+}
+
+/// Removes the overlay, the changes are lost.
+pub fn discard(image: &str) -> Result<()> {
+    let filename = overlay_filename(image);
+    if Path::new(&filename).exists() {
+        fs::remove_file(filename)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn temp_image(name: &str) -> String {
+        let path = env::temp_dir().join(format!("izilogmcz-{}-{}", process::id(), name));
+        let image = path.to_string_lossy().into_owned();
+        fs::write(&image, blank_image()).unwrap();
+        let _ = fs::remove_file(overlay_filename(&image));
+        image
+    }
+
+    #[test]
+    fn compacts_on_open() {
+        let image = temp_image("compact.MCZ");
+        let mut content = blank_image();
+        let mut overlay = Overlay::open(&image, &mut content).unwrap();
+        overlay.write_sector(5, 1, &[1; SECTOR_SIZE_IN_FILE]).unwrap();
+        overlay.write_sector(5, 1, &[2; SECTOR_SIZE_IN_FILE]).unwrap();
+        overlay.write_sector(3, 0, &[3; SECTOR_SIZE_IN_FILE]).unwrap();
+        drop(overlay);
+        assert_eq!(fs::metadata(overlay_filename(&image)).unwrap().len(), 3 * RECORD_SIZE as u64);
+
+        let mut content = blank_image();
+        Overlay::open(&image, &mut content).unwrap();
+        assert_eq!(fs::metadata(overlay_filename(&image)).unwrap().len(), 2 * RECORD_SIZE as u64);
+        assert_eq!(content[(5 * SECTOR_COUNT + 1) * SECTOR_SIZE_IN_FILE], 2);
+        assert_eq!(content[3 * SECTOR_COUNT * SECTOR_SIZE_IN_FILE], 3);
+
+        assert_eq!(commit(&image, false).unwrap(), 2);
+        assert!(!Path::new(&overlay_filename(&image)).exists());
+        assert_eq!(fs::read(&image).unwrap(), content);
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn commit_refuses_an_invalid_image() {
+        let image = temp_image("invalid.MCZ");
+        let mut content = blank_image();
+        Overlay::open(&image, &mut content).unwrap()
+            .write_sector(0, 0, &[1; SECTOR_SIZE_IN_FILE]).unwrap();
+        fs::write(&image, b"NOT AN IMAGE").unwrap();
+        assert!(commit(&image, false).is_err());
+        assert_eq!(fs::read(&image).unwrap(), b"NOT AN IMAGE");
+        discard(&image).unwrap();
+        fs::remove_file(&image).unwrap();
+    }
+}
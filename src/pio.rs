@@ -0,0 +1,305 @@
+use std::io::Result;
+
+use super::interrupts::InterruptDevice;
+use super::snapshot::{Reader, Writer};
+
+/*
+Z80-PIO parallel ports, ports 0xD8 to 0xDB.
+    0xD8: port A data
+    0xD9: port B data
+    0xDA: port A control
+    0xDB: port B control
+
+Control words:
+    bit 0 = 0: interrupt vector
+    xxxx1111: mode select, bits 7-6 for the mode
+        0: output, the device strobes to acknowledge the byte
+        1: input, the device strobes to load a byte
+        2: bidirectional, port A only
+        3: control, a direction word follows with 1 for the input bits
+    xxxx0111: interrupt control
+        bit 7: interrupt enable
+        bit 6: AND of the monitored bits instead of OR, mode 3 only
+        bit 5: monitored bits active high, mode 3 only
+        bit 4: a mask word follows with 1 for the bits not monitored
+    xxxx0011: bit 7 sets the interrupt enable
+
+Port A has higher interrupt priority than port B.
+*/
+
+const MODE_OUTPUT: u8 = 0;
+const MODE_INPUT: u8 = 1;
+const MODE_BIDIRECTIONAL: u8 = 2;
+const MODE_CONTROL: u8 = 3;
+
+const INT_ENABLE: u8 = 0x80;
+const INT_AND: u8 = 0x40;
+const INT_HIGH: u8 = 0x20;
+const INT_MASK_FOLLOWS: u8 = 0x10;
+
+/// Peripheral connected to the lines of the PIO ports.
+pub trait ParallelDevice {
+    /// The PIO drives the port lines. In output and bidirectional modes
+    /// returns true if the device strobes to acknowledge the byte.
+    fn write(&mut self, port: usize, value: u8) -> bool;
+    /// Lines driven by the device, read in control mode
+    fn read(&mut self, port: usize) -> u8;
+    /// Byte strobed by the device in input and bidirectional modes
+    fn strobe(&mut self, _port: usize) -> Option<u8> {
+        None
+    }
+}
+
+struct Port {
+    mode: u8,
+    output: u8,
+    input: u8,
+    direction: u8,
+    int_control: u8,
+    mask: u8,
+    vector: u8,
+    waiting_direction: bool,
+    waiting_mask: bool,
+    ready: bool,
+    matched: bool,
+    int_pending: bool,
+    int_in_service: bool,
+}
+
+impl Port {
+    fn new() -> Port {
+        Port {
+            mode: MODE_INPUT,
+            output: 0,
+            input: 0xff,
+            direction: 0xff,
+            int_control: 0,
+            mask: 0xff,
+            vector: 0,
+            waiting_direction: false,
+            waiting_mask: false,
+            ready: false,
+            matched: false,
+            int_pending: false,
+            int_in_service: false,
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        if self.waiting_direction {
+            self.waiting_direction = false;
+            self.direction = value;
+        } else if self.waiting_mask {
+            self.waiting_mask = false;
+            self.mask = value;
+        } else if value & 0x01 == 0 {
+            self.vector = value;
+        } else if value & 0x0f == 0x0f {
+            self.mode = value >> 6;
+            self.waiting_direction = self.mode == MODE_CONTROL;
+            self.ready = self.mode == MODE_INPUT || self.mode == MODE_BIDIRECTIONAL;
+        } else if value & 0x0f == 0x07 {
+            self.int_control = value & 0xf0;
+            if value & INT_MASK_FOLLOWS != 0 {
+                self.waiting_mask = true;
+                self.int_pending = false;
+            }
+        } else if value & 0x0f == 0x03 {
+            self.int_control = (self.int_control & !INT_ENABLE) | (value & INT_ENABLE);
+        }
+    }
+
+    fn request_interrupt(&mut self) {
+        if self.int_control & INT_ENABLE != 0 {
+            self.int_pending = true;
+        }
+    }
+
+    fn save_state(&self, writer: &mut Writer) {
+        writer.u8(self.mode);
+        writer.u8(self.output);
+        writer.u8(self.input);
+        writer.u8(self.direction);
+        writer.u8(self.int_control);
+        writer.u8(self.mask);
+        writer.u8(self.vector);
+        writer.bool(self.waiting_direction);
+        writer.bool(self.waiting_mask);
+        writer.bool(self.ready);
+        writer.bool(self.matched);
+        writer.bool(self.int_pending);
+        writer.bool(self.int_in_service);
+    }
+
+    fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        self.mode = reader.u8()?;
+        self.output = reader.u8()?;
+        self.input = reader.u8()?;
+        self.direction = reader.u8()?;
+        self.int_control = reader.u8()?;
+        self.mask = reader.u8()?;
+        self.vector = reader.u8()?;
+        self.waiting_direction = reader.bool()?;
+        self.waiting_mask = reader.bool()?;
+        self.ready = reader.bool()?;
+        self.matched = reader.bool()?;
+        self.int_pending = reader.bool()?;
+        self.int_in_service = reader.bool()?;
+        Ok(())
+    }
+
+    /// Evaluates the interrupt condition of the monitored input bits.
+    fn monitor(&mut self, lines: u8) {
+        let monitored = !self.mask & self.direction;
+        let active = (if self.int_control & INT_HIGH != 0 {lines} else {!lines}) & monitored;
+        let matched = if self.int_control & INT_AND != 0 {
+            monitored != 0 && active == monitored
+        } else {
+            active != 0
+        };
+        if matched && !self.matched {
+            self.request_interrupt();
+        }
+        self.matched = matched;
+    }
+}
+
+pub struct Pio {
+    ports: [Port; 2],
+    device: Option<Box<dyn ParallelDevice>>,
+}
+
+impl Pio {
+    pub fn new(device: Option<Box<dyn ParallelDevice>>) -> Pio {
+        Pio {
+            ports: [Port::new(), Port::new()],
+            device,
+        }
+    }
+
+    fn device_read(&mut self, port: usize) -> u8 {
+        match self.device {
+            Some(ref mut device) => device.read(port),
+            None => 0xff, // Lines pulled up
+        }
+    }
+
+    fn device_write(&mut self, port: usize, value: u8) -> bool {
+        match self.device {
+            Some(ref mut device) => device.write(port, value),
+            None => false,
+        }
+    }
+
+    pub fn data_in(&mut self, port: usize) -> u8 {
+        match self.ports[port].mode {
+            MODE_CONTROL => {
+                let lines = self.device_read(port);
+                let p = &self.ports[port];
+                (lines & p.direction) | (p.output & !p.direction)
+            },
+            MODE_OUTPUT => self.ports[port].output,
+            _ => {
+                // The input register is free for the next byte
+                let p = &mut self.ports[port];
+                p.ready = true;
+                p.input
+            },
+        }
+    }
+
+    pub fn data_out(&mut self, port: usize, value: u8) {
+        self.ports[port].output = value;
+        match self.ports[port].mode {
+            MODE_CONTROL => {
+                let lines = value & !self.ports[port].direction;
+                self.device_write(port, lines);
+            },
+            MODE_OUTPUT | MODE_BIDIRECTIONAL => {
+                let acknowledged = self.device_write(port, value);
+                if acknowledged {
+                    self.ports[port].request_interrupt();
+                }
+            },
+            _ => {}, // Stored for a later mode change
+        }
+    }
+
+    pub fn control_out(&mut self, port: usize, value: u8) {
+        self.ports[port].write_control(value);
+    }
+
+    /// Saves the state of the ports, the state of the device is not saved.
+    pub(crate) fn save_state(&self, writer: &mut Writer) {
+        for port in self.ports.iter() {
+            port.save_state(writer);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, reader: &mut Reader) -> Result<()> {
+        for port in self.ports.iter_mut() {
+            port.load_state(reader)?;
+        }
+        Ok(())
+    }
+
+    /// Polls the device for strobes and the monitored lines.
+    pub fn advance(&mut self) {
+        for port in 0..self.ports.len() {
+            match self.ports[port].mode {
+                MODE_INPUT | MODE_BIDIRECTIONAL if self.ports[port].ready => {
+                    let strobe = match self.device {
+                        Some(ref mut device) => device.strobe(port),
+                        None => None,
+                    };
+                    if let Some(value) = strobe {
+                        let p = &mut self.ports[port];
+                        p.input = value;
+                        p.ready = false;
+                        p.request_interrupt();
+                    }
+                },
+                MODE_CONTROL => {
+                    let lines = self.device_read(port);
+                    self.ports[port].monitor(lines);
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+impl InterruptDevice for Pio {
+    fn int_pending(&self) -> bool {
+        for port in self.ports.iter() {
+            if port.int_in_service {
+                return false;
+            }
+            if port.int_pending {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn int_in_service(&self) -> bool {
+        self.ports.iter().any(|p| p.int_in_service)
+    }
+
+    fn int_ack(&mut self) -> u8 {
+        for port in self.ports.iter_mut() {
+            if port.int_pending {
+                port.int_pending = false;
+                port.int_in_service = true;
+                return port.vector;
+            }
+        }
+        0xff
+    }
+
+    fn reti(&mut self) {
+        if let Some(port) = self.ports.iter_mut().find(|p| p.int_in_service) {
+            port.int_in_service = false;
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::Write;
+
+use super::pio::ParallelDevice;
+
+/*
+Line printer on the PIO, as used by the RIO PRINTER driver:
+    Port A in control mode:
+        bit 0 (OUT): STROBE, the printer takes the data on the falling edge
+        bit 1 (OUT): PRIME, low to reset the printer
+        bit 4 (IN): BUSY, high while the printer is busy
+    Port B in output mode: the character to print
+
+The printer is never busy, the characters are sent to the host sink. If
+the sink fails the output is dropped, with a warning the first time.
+*/
+
+const PORT_CONTROL: usize = 0;
+const PORT_DATA: usize = 1;
+
+const STROBE: u8 = 0x01;
+const PRIME: u8 = 0x02;
+
+pub struct Printer {
+    sink: Box<dyn Write>,
+    data: u8,
+    control: u8,
+    failed: bool,
+}
+
+impl Printer {
+    pub fn new(sink: Box<dyn Write>) -> Printer {
+        Printer {
+            sink,
+            data: 0,
+            control: STROBE | PRIME,
+            failed: false,
+        }
+    }
+}
+
+impl ParallelDevice for Printer {
+    fn write(&mut self, port: usize, value: u8) -> bool {
+        match port {
+            PORT_CONTROL => {
+                let falling = self.control & !value;
+                self.control = value;
+                if falling & STROBE != 0 && value & PRIME != 0 && !self.failed {
+                    let result = self.sink.write_all(&[self.data])
+                        .and_then(|_| self.sink.flush());
+                    if let Err(err) = result {
+                        eprintln!("Printer: {}, the output is dropped", err);
+                        self.failed = true;
+                    }
+                }
+                false
+            },
+            PORT_DATA => {
+                self.data = value;
+                false
+            },
+            _ => false,
+        }
+    }
+
+    fn read(&mut self, _port: usize) -> u8 {
+        0 // Not busy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, Result};
+
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn print(printer: &mut Printer, ch: u8) {
+        printer.write(PORT_DATA, ch);
+        printer.write(PORT_CONTROL, PRIME);
+        printer.write(PORT_CONTROL, STROBE | PRIME);
+    }
+
+    #[test]
+    fn drops_the_output_when_the_sink_fails() {
+        let mut printer = Printer::new(Box::new(FailingSink));
+        print(&mut printer, b'A');
+        assert!(printer.failed);
+        print(&mut printer, b'B');
+        assert_eq!(printer.read(PORT_CONTROL), 0);
+    }
+}
@@ -0,0 +1,137 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write, Result};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use termios::*;
+
+use super::serial::{InputChannel, SerialDevice};
+
+/*
+Pseudo-terminal for the serial console. Terminal programs like minicom or
+screen open the slave side, the emulator uses the master side.
+
+The flow control is XON/XOFF: after a XOFF from the terminal program the
+USART reports the transmitter as busy until a XON is received. The PROM
+waits on TXRDY before sending each byte.
+*/
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+// Wait when there is nothing to read on the master
+const READ_WAIT: Duration = Duration::from_millis(10);
+
+pub struct PtySerial {
+    master: File,
+    // Kept open, reads on the master fail while the slave is closed
+    _slave: File,
+    slave_path: String,
+    input: InputChannel,
+    stopped: Arc<AtomicBool>,
+}
+
+impl PtySerial {
+    /// Allocates a pseudo-terminal.
+    pub fn open() -> Result<PtySerial> {
+        // The master is non blocking to not stall the emulation when no
+        // program reads the output.
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(Error::last_os_error());
+            }
+            master
+        };
+
+        let slave_path = unsafe {
+            let name = libc::ptsname(master.as_raw_fd());
+            if name.is_null() {
+                return Err(Error::last_os_error());
+            }
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        };
+
+        // Raw mode until a terminal program sets its own mode, the default
+        // echo would send the output back as input.
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&slave_path)?;
+        let mut slave_termios = Termios::from_fd(slave.as_raw_fd())?;
+        cfmakeraw(&mut slave_termios);
+        tcsetattr(slave.as_raw_fd(), TCSANOW, &slave_termios)?;
+
+        let (sender, input) = InputChannel::new();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut reader = master.try_clone()?;
+        let reader_stopped = stopped.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(size) if size > 0 => {
+                        for &ch in buf[..size].iter() {
+                            match ch {
+                                XOFF => reader_stopped.store(true, Ordering::Relaxed),
+                                XON => reader_stopped.store(false, Ordering::Relaxed),
+                                _ => if sender.send(ch).is_err() {
+                                    return;
+                                },
+                            }
+                        }
+                    },
+                    Err(ref err) if err.kind() != ErrorKind::WouldBlock
+                        && err.kind() != ErrorKind::Interrupted
+                        && err.raw_os_error() != Some(libc::EIO) => {
+                        eprintln!("PTY read failed: {}", err);
+                        return;
+                    },
+                    _ => thread::sleep(READ_WAIT),
+                }
+            }
+        });
+
+        Ok(PtySerial {
+            master,
+            _slave: slave,
+            slave_path,
+            input,
+            stopped,
+        })
+    }
+
+    /// Path of the slave device to open with a terminal program.
+    pub fn slave_path(&self) -> &str {
+        &self.slave_path
+    }
+}
+
+impl SerialDevice for PtySerial {
+    fn status(&mut self) -> bool {
+        self.input.status()
+    }
+
+    fn read(&mut self) -> u8 {
+        self.input.read()
+    }
+
+    fn write(&mut self, ch: u8) {
+        // Lost if the buffer is full, no program is reading
+        let _ = self.master.write(&[ch]);
+    }
+
+    fn transmit_ready(&mut self) -> bool {
+        !self.stopped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Write, Result};
+
+use super::serial::SerialDevice;
+
+/*
+Deterministic record and replay of the console input. The emulation is
+deterministic except for the input of the host, the recorder logs when
+each input is seen by the MCZ and the player feeds it back at the same
+instruction, for runs identical to the recorded one.
+
+The log is a text file with an event per line, the first number is the
+instruction count when the MCZ sees the event. The count is of emulator
+steps, the HALT cycles and the interrupt acceptances included:
+    INSTRUCTION key HH CONSUMED
+        a byte is received, the status has the byte ready from the
+        instruction given. CONSUMED is the instruction that reads it from
+        SERDAT, to detect the replays that diverge.
+    INSTRUCTION break
+        the BREAK key is pressed.
+    INSTRUCTION ready 0|1
+        the device holds or resumes the transmission, with the flow
+        control of the pty.
+Lines starting with # are comments.
+
+The record and the replay have to start from the same state, with the
+same images on the drives and the same snapshot if one is restored. The
+changes of disks with the control channel are not recorded.
+*/
+
+const HEADER: &str = "# MCZ-1 console recording";
+
+/// Serial device that logs the input of another one.
+pub struct Recorder {
+    device: Box<dyn SerialDevice>,
+    log: BufWriter<File>,
+    instructions: u64,
+    // Instruction when the byte not yet read was first seen
+    visible: Option<u64>,
+    ready: bool,
+}
+
+impl Recorder {
+    /// Records the input of the device to a new log file.
+    pub fn create(device: Box<dyn SerialDevice>, filename: &str) -> Result<Recorder> {
+        let mut log = BufWriter::new(File::create(filename)?);
+        writeln!(log, "{}", HEADER)?;
+        Ok(Recorder {
+            device,
+            log,
+            instructions: 0,
+            visible: None,
+            ready: true,
+        })
+    }
+
+    fn record(&mut self, instruction: u64, event: String) {
+        // The log is flushed on each event, to keep it when the emulator
+        // is killed
+        let result = writeln!(self.log, "{} {}", instruction, event)
+            .and_then(|_| self.log.flush());
+        if let Err(err) = result {
+            eprintln!("Recording: {}", err);
+        }
+    }
+}
+
+impl SerialDevice for Recorder {
+    fn status(&mut self) -> bool {
+        if self.visible.is_none() && self.device.status() {
+            self.visible = Some(self.instructions);
+        }
+        self.visible.is_some()
+    }
+
+    fn read(&mut self) -> u8 {
+        let ch = self.device.read();
+        let visible = self.visible.take().unwrap_or(self.instructions);
+        self.record(visible, format!("key {:02x} {}", ch, self.instructions));
+        ch
+    }
+
+    fn write(&mut self, ch: u8) {
+        self.device.write(ch);
+    }
+
+    fn transmit_ready(&mut self) -> bool {
+        let ready = self.device.transmit_ready();
+        if ready != self.ready {
+            self.ready = ready;
+            self.record(self.instructions, format!("ready {}", ready as u8));
+        }
+        ready
+    }
+
+    fn break_pressed(&mut self) -> bool {
+        let pressed = self.device.break_pressed();
+        if pressed {
+            self.record(self.instructions, "break".to_string());
+        }
+        pressed
+    }
+
+    fn debug_pressed(&mut self) -> bool {
+        self.device.debug_pressed()
+    }
+
+    fn advance(&mut self) {
+        self.instructions += 1;
+    }
+}
+
+/// Serial device that replays a log. The output goes to another device,
+/// that gets the input when the replay ends.
+pub struct Player {
+    device: Box<dyn SerialDevice>,
+    instructions: u64,
+    // Pending events of each kind, with the instruction count
+    keys: VecDeque<(u64, u8, u64)>,
+    breaks: VecDeque<u64>,
+    ready_changes: VecDeque<(u64, bool)>,
+    ready: bool,
+    diverged: bool,
+}
+
+impl Player {
+    pub fn load(device: Box<dyn SerialDevice>, filename: &str) -> Result<Player> {
+        let mut player = Player {
+            device,
+            instructions: 0,
+            keys: VecDeque::new(),
+            breaks: VecDeque::new(),
+            ready_changes: VecDeque::new(),
+            ready: true,
+            diverged: false,
+        };
+        for (i, line) in fs::read_to_string(filename)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            player.parse_event(line).ok_or_else(|| Error::new(ErrorKind::InvalidData,
+                format!("Line {}: invalid event \"{}\"", i + 1, line)))?;
+        }
+        Ok(player)
+    }
+
+    fn parse_event(&mut self, line: &str) -> Option<()> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let instruction = fields.first()?.parse::<u64>().ok()?;
+        match fields[1..] {
+            ["key", ch, consumed] => self.keys.push_back((instruction,
+                u8::from_str_radix(ch, 16).ok()?, consumed.parse().ok()?)),
+            ["break"] => self.breaks.push_back(instruction),
+            ["ready", "0"] => self.ready_changes.push_back((instruction, false)),
+            ["ready", "1"] => self.ready_changes.push_back((instruction, true)),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Returns true when all the events have been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.keys.is_empty() && self.breaks.is_empty() && self.ready_changes.is_empty()
+    }
+
+    fn warn_divergence(&mut self, expected: u64) {
+        if !self.diverged {
+            self.diverged = true;
+            eprintln!("Replay: the run diverges, a key is read at instruction {} instead of {}",
+                self.instructions, expected);
+        }
+    }
+}
+
+impl SerialDevice for Player {
+    fn status(&mut self) -> bool {
+        match self.keys.front() {
+            Some(&(visible, _, _)) => visible <= self.instructions,
+            None => self.is_finished() && self.device.status(),
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.keys.pop_front() {
+            Some((_, ch, consumed)) => {
+                if consumed != self.instructions {
+                    self.warn_divergence(consumed);
+                }
+                ch
+            },
+            None if self.is_finished() => self.device.read(),
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, ch: u8) {
+        self.device.write(ch);
+    }
+
+    fn transmit_ready(&mut self) -> bool {
+        while let Some(&(instruction, ready)) = self.ready_changes.front() {
+            if instruction > self.instructions {
+                break;
+            }
+            self.ready = ready;
+            self.ready_changes.pop_front();
+        }
+        if self.is_finished() {
+            self.device.transmit_ready()
+        } else {
+            self.ready
+        }
+    }
+
+    fn break_pressed(&mut self) -> bool {
+        match self.breaks.front() {
+            Some(&instruction) if instruction <= self.instructions => {
+                self.breaks.pop_front();
+                true
+            },
+            Some(_) => false,
+            None => self.is_finished() && self.device.break_pressed(),
+        }
+    }
+
+    fn debug_pressed(&mut self) -> bool {
+        self.device.debug_pressed()
+    }
+
+    fn advance(&mut self) {
+        self.instructions += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::serial::MemorySerial;
+    use crate::snapshot;
+
+    fn temp_file(name: &str) -> String {
+        env::temp_dir().join(format!("izilogmcz-{}-{}", process::id(), name))
+            .to_string_lossy().into_owned()
+    }
+
+    // Runs the PROM monitor with a command typed in the middle of the run
+    fn run(emulator: &mut Emulator, console: &MemorySerial, input: &[u8]) {
+        emulator.run_for(300_000);
+        console.send(input);
+        emulator.run_for(1_500_000);
+    }
+
+    #[test]
+    fn replay_is_identical_to_the_record() {
+        let log = temp_file("replay.log");
+        let console = MemorySerial::new();
+        let recorder = Recorder::create(Box::new(console.clone()), &log).unwrap();
+        let mut recorded = Emulator::new(Vec::new(), Box::new(recorder));
+        run(&mut recorded, &console, b"D 1000 1010\r");
+        let recorded_output = console.take_output();
+        assert!(recorded_output.starts_with(b">D 1000 1010\r\n1000 00"));
+        assert!(fs::read_to_string(&log).unwrap().contains(" key 44 "));
+
+        let console = MemorySerial::new();
+        let player = Player::load(Box::new(console.clone()), &log).unwrap();
+        let mut replayed = Emulator::new(Vec::new(), Box::new(player));
+        run(&mut replayed, &console, b"");
+        assert_eq!(console.take_output(), recorded_output);
+        assert_eq!(replayed.cycles(), recorded.cycles());
+
+        let recorded_state = temp_file("recorded.snap");
+        let replayed_state = temp_file("replayed.snap");
+        snapshot::save(&mut recorded, &recorded_state).unwrap();
+        snapshot::save(&mut replayed, &replayed_state).unwrap();
+        assert_eq!(fs::read(&replayed_state).unwrap(), fs::read(&recorded_state).unwrap());
+        for path in [log, recorded_state, replayed_state] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_events_fail() {
+        let log = temp_file("invalid.log");
+        fs::write(&log, format!("{}\n10 key 41 12\n20 press\n", HEADER)).unwrap();
+        let err = Player::load(Box::new(MemorySerial::new()), &log).err().unwrap();
+        assert_eq!(err.to_string(), "Line 3: invalid event \"20 press\"");
+        fs::remove_file(&log).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Write, Result};
+
+use super::emulator::{Emulator, CLOCK_HZ};
+use super::serial::MemorySerial;
+
+/*
+Automation of the console, to run the MCZ without a human at the keyboard.
+A script has a step per line:
+    send TEXT       types the text
+    expect TEXT     waits until the text is in the output
+    wait SECONDS    lets the machine run
+    timeout SECONDS time limit of the next expect steps, 60 by default
+
+The empty lines and the lines starting with # are ignored. The text can
+be quoted, to keep the spaces at the ends, and has the escapes \r, \n, \t,
+\\, \" and \xHH. The times are emulated time, a script runs the same at any
+host speed.
+
+Example:
+    expect "RIO REL"
+    send "CAT\r"
+    expect "OS.INIT"
+*/
+
+// The boot with the floppy controller ports takes several seconds
+const DEFAULT_TIMEOUT: f64 = 60.0;
+
+// T-states between output checks, 10 ms
+pub const POLL_CYCLES: u64 = CLOCK_HZ / 100;
+
+enum Step {
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+    Wait(f64),
+    Timeout(f64),
+}
+
+pub struct Script {
+    // Steps with their line numbers
+    steps: Vec<(usize, Step)>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script> {
+        let mut steps = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let step = parse_step(line).map_err(|err|
+                Error::new(ErrorKind::InvalidData, format!("Line {}: {}", i + 1, err)))?;
+            steps.push((i + 1, step));
+        }
+        Ok(Script { steps })
+    }
+
+    pub fn load(filename: &str) -> Result<Script> {
+        Script::parse(&fs::read_to_string(filename)?)
+    }
+
+    /// Runs the script on an emulator that has the console as serial
+    /// device. The console output is copied to the echo. Fails when an
+    /// expect times out or the CPU stops.
+    pub fn run(&self, emulator: &mut Emulator, console: &MemorySerial,
+            echo: &mut dyn Write) -> Result<()> {
+        let mut watcher = Watcher::new(console, echo);
+        let mut timeout = DEFAULT_TIMEOUT;
+
+        for (line, step) in self.steps.iter() {
+            match step {
+                Step::Send(text) => console.send(text),
+                Step::Wait(seconds) => {
+                    let end = emulator.cycles() + to_cycles(*seconds);
+                    while emulator.cycles() < end && !emulator.is_stopped() {
+                        emulator.run_for(POLL_CYCLES);
+                        watcher.collect()?;
+                    }
+                },
+                Step::Timeout(seconds) => timeout = *seconds,
+                Step::Expect(text) => {
+                    let end = emulator.cycles() + to_cycles(timeout);
+                    loop {
+                        if watcher.find(text) {
+                            break;
+                        }
+                        if emulator.is_stopped() {
+                            return Err(Error::other(format!(
+                                "Line {}: the CPU stopped expecting \"{}\"", line, show(text))));
+                        }
+                        if emulator.cycles() >= end {
+                            return Err(Error::new(ErrorKind::TimedOut, format!(
+                                "Line {}: timeout expecting \"{}\"", line, show(text))));
+                        }
+                        emulator.run_for(POLL_CYCLES);
+                        watcher.collect()?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output of the console not yet matched by an expect.
+pub struct Watcher<'a> {
+    console: &'a MemorySerial,
+    echo: &'a mut dyn Write,
+    output: Vec<u8>,
+}
+
+impl<'a> Watcher<'a> {
+    pub fn new(console: &'a MemorySerial, echo: &'a mut dyn Write) -> Watcher<'a> {
+        Watcher {
+            console,
+            echo,
+            output: Vec::new(),
+        }
+    }
+
+    /// Takes the new output of the console. Returns true if there was any.
+    pub fn collect(&mut self) -> Result<bool> {
+        let output = self.console.take_output();
+        if output.is_empty() {
+            return Ok(false);
+        }
+        self.echo.write_all(&output)?;
+        self.echo.flush()?;
+        // The MCZ may set the parity bit
+        self.output.extend(output.iter().map(|&ch| ch & 0x7f));
+        Ok(true)
+    }
+
+    /// Returns true if the output not consumed ends with the text.
+    pub fn ends_with(&self, text: &[u8]) -> bool {
+        self.output.ends_with(text)
+    }
+
+    /// Consumes all the output.
+    pub fn clear(&mut self) {
+        self.output.clear();
+    }
+
+    /// Looks for the text, the output up to the match is consumed.
+    pub fn find(&mut self, text: &[u8]) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+        match self.output.windows(text.len()).position(|window| window == text) {
+            Some(position) => {
+                self.output.drain(..position + text.len());
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+pub fn to_cycles(seconds: f64) -> u64 {
+    (seconds * CLOCK_HZ as f64) as u64
+}
+
+fn parse_step(line: &str) -> std::result::Result<Step, String> {
+    let (command, argument) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+    match command {
+        "send" => Ok(Step::Send(parse_text(argument)?)),
+        "expect" => Ok(Step::Expect(parse_text(argument)?)),
+        "wait" => Ok(Step::Wait(parse_seconds(argument)?)),
+        "timeout" => Ok(Step::Timeout(parse_seconds(argument)?)),
+        _ => Err(format!("Unknown step \"{}\"", command)),
+    }
+}
+
+fn parse_seconds(argument: &str) -> std::result::Result<f64, String> {
+    match argument.parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 => Ok(seconds),
+        _ => Err(format!("Invalid time \"{}\"", argument)),
+    }
+}
+
+fn parse_text(argument: &str) -> std::result::Result<Vec<u8>, String> {
+    let text = if argument.len() >= 2 && argument.starts_with('"') && argument.ends_with('"') {
+        &argument[1..argument.len() - 1]
+    } else {
+        argument
+    };
+
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(value) if hex.len() == 2 => bytes.push(value),
+                    _ => return Err(format!("Invalid escape \\x{}", hex)),
+                }
+            },
+            Some(other) => return Err(format!("Invalid escape \\{}", other)),
+            None => return Err("Escape at the end of the text".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Text for the error messages.
+fn show(text: &[u8]) -> String {
+    text.escape_ascii().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::SerialDevice;
+
+    #[test]
+    fn parse_steps() {
+        let script = Script::parse("# Boot\n\nexpect \"RIO REL\"\n  send CAT\\r\ntimeout 2.5\nwait 1\n").unwrap();
+        let steps: Vec<_> = script.steps.iter().map(|(line, step)| (*line, step)).collect();
+        assert_eq!(steps.len(), 4);
+        assert!(matches!(steps[0], (3, Step::Expect(text)) if text == b"RIO REL"));
+        assert!(matches!(steps[1], (4, Step::Send(text)) if text == b"CAT\r"));
+        assert!(matches!(steps[2], (5, Step::Timeout(seconds)) if *seconds == 2.5));
+        assert!(matches!(steps[3], (6, Step::Wait(seconds)) if *seconds == 1.0));
+    }
+
+    #[test]
+    fn parse_text_escapes() {
+        assert_eq!(parse_text("\" A \\\"B\\\" \"").unwrap(), b" A \"B\" ");
+        assert_eq!(parse_text("\\x1b\\t\\n\\\\").unwrap(), b"\x1b\t\n\\");
+        assert!(parse_text("\\x1").is_err());
+        assert!(parse_text("\\q").is_err());
+        assert!(parse_text("END\\").is_err());
+    }
+
+    #[test]
+    fn parse_errors_have_the_line() {
+        let err = Script::parse("send A\nwait -1\n").err().unwrap();
+        assert_eq!(err.to_string(), "Line 2: Invalid time \"-1\"");
+        let err = Script::parse("type A").err().unwrap();
+        assert_eq!(err.to_string(), "Line 1: Unknown step \"type\"");
+    }
+
+    #[test]
+    fn watcher_consumes_up_to_the_match() {
+        let console = MemorySerial::new();
+        let mut echo = Vec::new();
+        let mut watcher = Watcher::new(&console, &mut echo);
+        let mut serial = console.clone();
+        for &ch in b"%CAT\r\nOS\xc9INIT\r\n%" {
+            serial.write(ch);
+        }
+        assert!(watcher.collect().unwrap());
+        // The parity bit is cleared
+        assert!(watcher.find(b"OSIINIT"));
+        assert!(!watcher.find(b"CAT"));
+        assert!(watcher.ends_with(b"%"));
+        watcher.clear();
+        assert!(!watcher.ends_with(b"%"));
+        assert!(!watcher.collect().unwrap());
+    }
+}
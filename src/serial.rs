@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Result, stdout};
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Device connected to the USART serial port, SERDAT and SERCON. The
+/// PROM and RIO use it as the system console.
+pub trait SerialDevice {
+    /// Returns true if a byte has been received
+    fn status(&mut self) -> bool;
+    /// Reads the received byte, waits if there is none
+    fn read(&mut self) -> u8;
+    /// Transmits a byte
+    fn write(&mut self, ch: u8);
+    /// Returns false while the device holds the transmission
+    fn transmit_ready(&mut self) -> bool {
+        true
+    }
+    /// Returns true once after the BREAK key is pressed
+    fn break_pressed(&mut self) -> bool {
+        false
+    }
+    /// Returns true once after the key to enter the debugger is pressed
+    fn debug_pressed(&mut self) -> bool {
+        false
+    }
+    /// Called once per emulator step: an instruction, a HALT cycle or an
+    /// interrupt acceptance. The replay counts these steps.
+    fn advance(&mut self) {}
+}
+
+/// Serial device with in-memory buffers. The clones share the buffers, a
+/// clone is kept to send input and to collect the output.
+#[derive(Clone, Default)]
+pub struct MemorySerial {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemorySerial {
+    pub fn new() -> MemorySerial {
+        Default::default()
+    }
+
+    /// Queues bytes to be received by the MCZ.
+    pub fn send(&self, data: &[u8]) {
+        self.input.borrow_mut().extend(data);
+    }
+
+    /// Returns true when all the bytes sent have been read by the MCZ.
+    pub fn is_input_empty(&self) -> bool {
+        self.input.borrow().is_empty()
+    }
+
+    /// Returns and clears the bytes transmitted by the MCZ.
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.replace(Vec::new())
+    }
+}
+
+impl SerialDevice for MemorySerial {
+    fn status(&mut self) -> bool {
+        !self.input.borrow().is_empty()
+    }
+
+    fn read(&mut self) -> u8 {
+        // There is nothing to wait for, a null is received
+        self.input.borrow_mut().pop_front().unwrap_or(0)
+    }
+
+    fn write(&mut self, ch: u8) {
+        self.output.borrow_mut().push(ch);
+    }
+}
+
+/// Input of the serial devices received on a thread. The bytes are sent
+/// on the channel, the device polls them without blocking.
+pub struct InputChannel {
+    receiver: Receiver<u8>,
+    next_char: Option<u8>,
+}
+
+impl InputChannel {
+    /// Returns the sender for the receiving thread and the channel.
+    pub fn new() -> (Sender<u8>, InputChannel) {
+        let (sender, receiver) = channel();
+        (sender, InputChannel {
+            receiver,
+            next_char: None,
+        })
+    }
+
+    /// Returns true if a byte has been received.
+    pub fn status(&mut self) -> bool {
+        if self.next_char.is_none() {
+            match self.receiver.try_recv() {
+                Ok(ch) => self.next_char = Some(ch),
+                Err(_) => {
+                    // Avoid 100% CPU usage waiting for input.
+                    thread::sleep(Duration::from_nanos(100));
+                }
+            }
+        }
+        self.next_char.is_some()
+    }
+
+    /// Reads the received byte. Blocks waiting for it, a null is received
+    /// after the end of the input.
+    pub fn read(&mut self) -> u8 {
+        match self.next_char.take() {
+            Some(ch) => ch,
+            None => self.receiver.recv().unwrap_or(0),
+        }
+    }
+}
+
+/// Serial device on host streams: files, named pipes or a TCP connection.
+/// The input is read on a separate thread to not block the emulation.
+pub struct StreamSerial {
+    input: InputChannel,
+    output: Box<dyn Write>,
+}
+
+impl StreamSerial {
+    pub fn new(mut input: Box<dyn Read + Send>, output: Box<dyn Write>) -> StreamSerial {
+        let (sender, channel) = InputChannel::new();
+        thread::spawn(move || {
+            let mut buf = [0; 256];
+            while let Ok(size) = input.read(&mut buf) {
+                if size == 0 {
+                    break; // End of input
+                }
+                for &ch in buf[..size].iter() {
+                    if sender.send(ch).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        StreamSerial {
+            input: channel,
+            output,
+        }
+    }
+
+    /// Reads from a file or pipe and writes to another one, or to stdout if
+    /// none is given.
+    pub fn open_files(input: &str, output: Option<&str>) -> Result<StreamSerial> {
+        let input = File::open(input)?;
+        let output: Box<dyn Write> = match output {
+            Some(output) => Box::new(OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output)?),
+            None => Box::new(stdout()),
+        };
+        Ok(StreamSerial::new(Box::new(input), output))
+    }
+
+    /// Connects to a TCP server.
+    pub fn connect(address: &str) -> Result<StreamSerial> {
+        let stream = TcpStream::connect(address)?;
+        Ok(StreamSerial::new(Box::new(stream.try_clone()?), Box::new(stream)))
+    }
+}
+
+impl SerialDevice for StreamSerial {
+    fn status(&mut self) -> bool {
+        self.input.status()
+    }
+
+    fn read(&mut self) -> u8 {
+        self.input.read()
+    }
+
+    fn write(&mut self, ch: u8) {
+        // A closed output is ignored, like a disconnected terminal
+        let _ = self.output.write_all(&[ch]).and_then(|_| self.output.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_channel_polls_and_reads() {
+        let (sender, mut input) = InputChannel::new();
+        assert!(!input.status());
+        sender.send(b'A').unwrap();
+        sender.send(b'B').unwrap();
+        assert!(input.status());
+        assert_eq!(input.read(), b'A');
+        assert_eq!(input.read(), b'B');
+        drop(sender);
+        assert!(!input.status());
+        assert_eq!(input.read(), 0);
+    }
+}
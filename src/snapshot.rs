@@ -0,0 +1,375 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use iz80::*;
+
+use super::emulator::Emulator;
+
+/*
+Snapshots of the machine, to save the state to a file and resume from it
+later. The file has a signature and a version, then the state of each part
+in a fixed order:
+    CPU registers, alternate registers included, and interrupt state
+    T-states elapsed
+    RAM, 64K
+    disk controller, with the sectors written on each drive
+    CTC and PIO
+
+The values are little endian. The async floppy requests in progress are
+saved with the RAM of the PROM driver and the CTC channel that completes
+them.
+
+The disks are not saved, only the sectors written since the emulator
+started and a hash of the other sectors of each drive. The restore has to
+be done with the same images on the drives, it fails before writing if a
+hash differs. The sectors written that differ are written again. The USART has no state, the
+characters typed and not yet read by the MCZ are not saved.
+*/
+
+const SIGNATURE: &[u8] = b"MCZ-1 SNAPSHOT\x1a";
+const VERSION: u8 = 2;
+
+/// Saves the state of the emulator to a file.
+pub fn save(emulator: &mut Emulator, path: &str) -> Result<()> {
+    let mut writer = Writer::new();
+    writer.bytes(SIGNATURE);
+    writer.u8(VERSION);
+    emulator.save_state(&mut writer);
+    fs::write(path, writer.data)
+}
+
+/// Restores the state of the emulator from a file. The emulator has to
+/// have the same images on the drives as when saved.
+pub fn restore(emulator: &mut Emulator, path: &str) -> Result<()> {
+    let content = fs::read(path)?;
+    let mut reader = Reader::new(&content);
+    if reader.bytes(SIGNATURE.len()).ok() != Some(SIGNATURE) {
+        return Err(invalid("Not a snapshot file".to_string()));
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(invalid(format!("Unsupported snapshot version {}", version)));
+    }
+    emulator.load_state(&mut reader)?;
+    if reader.position != content.len() {
+        return Err(invalid("Invalid snapshot, data after the end".to_string()));
+    }
+    Ok(())
+}
+
+/// Serialization of the state of the devices.
+pub(crate) struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+/// Deserialization of the state of the devices, fails if truncated.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader {
+            data,
+            position: 0,
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid(format!("Invalid snapshot, bool value {}", value))),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position + count)
+            .ok_or_else(|| invalid("Truncated snapshot".to_string()))?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+pub(crate) fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+const REGISTERS: [Reg16; 7] = [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL,
+    Reg16::IX, Reg16::IY, Reg16::SP];
+const ALTERNATE_REGISTERS: [Reg16; 4] = [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL];
+
+pub(crate) fn save_registers(cpu: &mut Cpu, trace: bool, writer: &mut Writer) {
+    let registers = cpu.registers();
+    for &register in REGISTERS.iter() {
+        writer.u16(registers.get16(register));
+    }
+    writer.u16(registers.pc());
+    writer.u8(registers.get8(Reg8::I));
+    writer.u8(registers.get8(Reg8::R));
+
+    exchange(cpu, trace);
+    for &register in ALTERNATE_REGISTERS.iter() {
+        writer.u16(cpu.registers().get16(register));
+    }
+    exchange(cpu, trace);
+}
+
+pub(crate) fn load_registers(cpu: &mut Cpu, trace: bool, reader: &mut Reader) -> Result<()> {
+    let mut values = [0; REGISTERS.len()];
+    for value in values.iter_mut() {
+        *value = reader.u16()?;
+    }
+    let pc = reader.u16()?;
+    let i = reader.u8()?;
+    let r = reader.u8()?;
+
+    exchange(cpu, trace);
+    for &register in ALTERNATE_REGISTERS.iter() {
+        let value = reader.u16()?;
+        cpu.registers().set16(register, value);
+    }
+    exchange(cpu, trace);
+
+    let registers = cpu.registers();
+    for (&register, &value) in REGISTERS.iter().zip(values.iter()) {
+        registers.set16(register, value);
+    }
+    registers.set_pc(pc);
+    registers.set8(Reg8::I, i);
+    registers.set8(Reg8::R, r);
+    Ok(())
+}
+
+/// Bus with the same opcode on every address.
+struct Opcode(u8);
+
+impl Machine for Opcode {
+    fn peek(&self, _address: u16) -> u8 {
+        self.0
+    }
+
+    fn poke(&mut self, _address: u16, _value: u8) {}
+
+    fn port_in(&mut self, _address: u16) -> u8 {
+        0xff
+    }
+
+    fn port_out(&mut self, _address: u16, _value: u8) {}
+}
+
+/// Swaps the registers with the alternate registers. iz80 has no access to
+/// the alternate registers, EX AF,AF' and EXX are executed with PC and R
+/// preserved. They are not traced, trace is the CPU trace state to restore
+/// as iz80 can't report it.
+pub(crate) fn exchange(cpu: &mut Cpu, trace: bool) {
+    let pc = cpu.registers().pc();
+    let r = cpu.registers().get8(Reg8::R);
+    cpu.set_trace(false);
+    cpu.execute_instruction(&mut Opcode(0x08)); // EX AF,AF'
+    cpu.execute_instruction(&mut Opcode(0xd9)); // EXX
+    cpu.set_trace(trace);
+    cpu.registers().set_pc(pc);
+    cpu.registers().set8(Reg8::R, r);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::media::{self, Disk, Media};
+    use crate::serial::MemorySerial;
+
+    fn new_emulator() -> Emulator {
+        let mut media = Media::new_from_bytes(&media::blank_image());
+        media.write_protected = false;
+        let drives: Vec<Box<dyn Disk>> = vec![Box::new(media)];
+        Emulator::new(drives, Box::new(MemorySerial::new()))
+    }
+
+    fn state(emulator: &mut Emulator) -> Vec<u8> {
+        let mut writer = Writer::new();
+        emulator.save_state(&mut writer);
+        writer.data
+    }
+
+    fn temp_file(name: &str) -> String {
+        env::temp_dir().join(format!("izilogmcz-{}-{}", process::id(), name))
+            .to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = Writer::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u32(0x789abcde);
+        writer.u64(0x0123456789abcdef);
+        writer.bytes(b"MCZ");
+        assert_eq!(writer.data[2..4], [0x56, 0x34]);
+
+        let mut reader = Reader::new(&writer.data);
+        assert_eq!(reader.u8().unwrap(), 0x12);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0x3456);
+        assert_eq!(reader.u32().unwrap(), 0x789abcde);
+        assert_eq!(reader.u64().unwrap(), 0x0123456789abcdef);
+        assert_eq!(reader.bytes(3).unwrap(), b"MCZ");
+        assert_eq!(reader.u8().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn invalid_bool_fails() {
+        assert!(Reader::new(&[2]).bool().is_err());
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let mut emulator = new_emulator();
+        emulator.run_for(100_000);
+        let registers = emulator.cpu().registers();
+        registers.set16(Reg16::IX, 0x1234);
+        registers.set8(Reg8::I, 0x56);
+        exchange(emulator.cpu(), false);
+        emulator.cpu().registers().set16(Reg16::HL, 0x789a);
+        exchange(emulator.cpu(), false);
+        let saved = state(&mut emulator);
+
+        let mut restored = new_emulator();
+        restored.load_state(&mut Reader::new(&saved)).unwrap();
+        assert_eq!(state(&mut restored), saved);
+        assert_eq!(restored.cycles(), emulator.cycles());
+        assert_eq!(restored.cpu().registers().get16(Reg16::IX), 0x1234);
+        assert_eq!(restored.cpu().registers().get8(Reg8::I), 0x56);
+        exchange(restored.cpu(), false);
+        assert_eq!(restored.cpu().registers().get16(Reg16::HL), 0x789a);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = temp_file("round-trip.snap");
+        let mut emulator = new_emulator();
+        emulator.run_for(100_000);
+        save(&mut emulator, &path).unwrap();
+        let content = fs::read(&path).unwrap();
+        assert!(content.starts_with(SIGNATURE));
+        assert_eq!(content[SIGNATURE.len()], VERSION);
+
+        let mut restored = new_emulator();
+        restore(&mut restored, &path).unwrap();
+        assert_eq!(state(&mut restored), state(&mut emulator));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restores_the_written_sectors() {
+        let mut emulator = new_emulator();
+        let data = [0x5a; media::SECTOR_SIZE_IN_FILE];
+        emulator.machine().mdc().write_sector(0, 10, 3, &data).unwrap();
+        let saved = state(&mut emulator);
+
+        let mut restored = new_emulator();
+        restored.load_state(&mut Reader::new(&saved)).unwrap();
+        assert_eq!(restored.machine().mdc().drive(0).unwrap().read_sector(10, 3), data);
+        assert_eq!(state(&mut restored), saved);
+    }
+
+    #[test]
+    fn refuses_another_disk() {
+        let mut emulator = new_emulator();
+        emulator.machine().mdc().write_sector(0, 10, 3, &[0x5a; media::SECTOR_SIZE_IN_FILE]).unwrap();
+        let saved = state(&mut emulator);
+
+        let mut other = new_emulator();
+        let mdc = other.machine().mdc();
+        mdc.write_sector(0, 20, 1, &[0xa5; media::SECTOR_SIZE_IN_FILE]).unwrap();
+        assert!(other.load_state(&mut Reader::new(&saved)).is_err());
+        assert_ne!(other.machine().mdc().drive(0).unwrap().read_sector(10, 3)[0], 0x5a);
+
+        let mut empty = Emulator::new(Vec::new(), Box::new(MemorySerial::new()));
+        assert!(empty.load_state(&mut Reader::new(&saved)).is_err());
+        let mut more = new_emulator();
+        assert!(more.insert_disk(1, Box::new(Media::new_from_bytes(&media::blank_image()))).is_ok());
+        assert!(more.load_state(&mut Reader::new(&saved)).is_err());
+    }
+
+    #[test]
+    fn invalid_files_fail() {
+        let path = temp_file("invalid.snap");
+        let mut emulator = new_emulator();
+        save(&mut emulator, &path).unwrap();
+        let content = fs::read(&path).unwrap();
+
+        let mut bad_signature = content.clone();
+        bad_signature[0] ^= 0xff;
+        let mut bad_version = content.clone();
+        bad_version[SIGNATURE.len()] = VERSION + 1;
+        let mut trailing = content.clone();
+        trailing.push(0);
+        let truncated = content[..content.len() - 1].to_vec();
+        for bad in [bad_signature, bad_version, trailing, truncated] {
+            fs::write(&path, bad).unwrap();
+            let err = restore(&mut new_emulator(), &path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
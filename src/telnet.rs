@@ -0,0 +1,154 @@
+use std::io::{Read, Write, Result};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::serial::{InputChannel, SerialDevice};
+
+/*
+Telnet server for the serial console. One client is attached at a time, a
+new connection replaces the previous one. Without a client the output is
+discarded.
+
+The server negotiates character mode: it will echo, as the MCZ echoes the
+input, and suppresses go ahead. The telnet BREAK command is the BREAK key.
+*/
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const BRK: u8 = 243;
+const SE: u8 = 240;
+
+const OPTION_ECHO: u8 = 1;
+const OPTION_SUPPRESS_GO_AHEAD: u8 = 3;
+const OPTION_LINEMODE: u8 = 34;
+
+const NEGOTIATION: [u8; 12] = [
+    IAC, WILL, OPTION_ECHO,
+    IAC, WILL, OPTION_SUPPRESS_GO_AHEAD,
+    IAC, DO, OPTION_SUPPRESS_GO_AHEAD,
+    IAC, DONT, OPTION_LINEMODE,
+];
+
+pub struct TelnetSerial {
+    input: InputChannel,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    break_pressed: Arc<AtomicBool>,
+}
+
+impl TelnetSerial {
+    /// Listens for telnet clients on the address, like "0.0.0.0:2323".
+    pub fn listen(address: &str) -> Result<TelnetSerial> {
+        let listener = TcpListener::bind(address)?;
+        let (sender, input) = InputChannel::new();
+        let client = Arc::new(Mutex::new(None));
+        let break_pressed = Arc::new(AtomicBool::new(false));
+
+        let client_listener = client.clone();
+        let break_listener = break_pressed.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(err) = attach(stream, &client_listener, &sender, &break_listener) {
+                    eprintln!("Telnet connection failed: {}", err);
+                }
+            }
+        });
+
+        Ok(TelnetSerial {
+            input,
+            client,
+            break_pressed,
+        })
+    }
+}
+
+fn attach(mut stream: TcpStream, client: &Arc<Mutex<Option<TcpStream>>>,
+        sender: &Sender<u8>, break_pressed: &Arc<AtomicBool>) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.write_all(&NEGOTIATION)?;
+
+    let reader = stream.try_clone()?;
+    if let Some(previous) = client.lock().unwrap().replace(stream) {
+        let _ = previous.shutdown(Shutdown::Both);
+    }
+
+    let sender = sender.clone();
+    let break_pressed = break_pressed.clone();
+    thread::spawn(move || receive(reader, sender, break_pressed));
+    Ok(())
+}
+
+/// Removes the telnet commands from the input. Enter is sent by the
+/// clients as CR NUL or CR LF, the second byte is dropped.
+fn receive(mut stream: TcpStream, sender: Sender<u8>, break_pressed: Arc<AtomicBool>) {
+    let mut buf = [0; 256];
+    let mut command = Vec::new();
+    let mut after_cr = false;
+    while let Ok(size) = stream.read(&mut buf) {
+        if size == 0 {
+            break; // Disconnected
+        }
+        for &byte in buf[..size].iter() {
+            if !command.is_empty() {
+                command.push(byte);
+                let done = match command[1] {
+                    IAC => {
+                        // Escaped 255
+                        let _ = sender.send(IAC);
+                        true
+                    },
+                    WILL | WONT | DO | DONT => command.len() == 3,
+                    SB => command.ends_with(&[IAC, SE]),
+                    BRK => {
+                        break_pressed.store(true, Ordering::Relaxed);
+                        true
+                    },
+                    _ => true,
+                };
+                if done {
+                    command.clear();
+                }
+            } else if byte == IAC {
+                command.push(byte);
+            } else if after_cr && (byte == 0 || byte == b'\n') {
+                after_cr = false;
+            } else {
+                after_cr = byte == b'\r';
+                if sender.send(byte).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl SerialDevice for TelnetSerial {
+    fn status(&mut self) -> bool {
+        self.input.status()
+    }
+
+    fn read(&mut self) -> u8 {
+        self.input.read()
+    }
+
+    fn write(&mut self, ch: u8) {
+        let mut client = self.client.lock().unwrap();
+        if let Some(ref mut stream) = *client {
+            let data: &[u8] = if ch == IAC {&[IAC, IAC]} else {&[ch]};
+            if stream.write_all(data).is_err() {
+                // The client is gone, wait for the next one
+                *client = None;
+            }
+        }
+    }
+
+    fn break_pressed(&mut self) -> bool {
+        self.break_pressed.swap(false, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,83 @@
+use super::mcz_machine::MczMachine;
+
+/*
+Approximate duration of the Z80 instructions in T-states. iz80 does not
+count cycles, the devices timed by the clock (the disk rotation and the
+CTC) use this estimation. Conditional jumps and calls are counted as taken
+and the repeating block instructions as a single iteration.
+*/
+
+const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    4, 10,  7,  6,  4,  4,  7,  4,  4, 11,  7,  6,  4,  4,  7,  4, // 0x00
+   13, 10,  7,  6,  4,  4,  7,  4, 12, 11,  7,  6,  4,  4,  7,  4, // 0x10
+   12, 10, 16,  6,  4,  4,  7,  4, 12, 11, 16,  6,  4,  4,  7,  4, // 0x20
+   12, 10, 13,  6, 11, 11, 10,  4, 12, 11, 13,  6,  4,  4,  7,  4, // 0x30
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x40
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x50
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x60
+    7,  7,  7,  7,  7,  7,  4,  7,  4,  4,  4,  4,  4,  4,  7,  4, // 0x70
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x80
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0x90
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0xa0
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4, // 0xb0
+   11, 10, 10, 10, 17, 11,  7, 11, 11, 10, 10,  8, 17, 17,  7, 11, // 0xc0
+   11, 10, 10, 11, 17, 11,  7, 11, 11,  4, 10, 11, 17,  4,  7, 11, // 0xd0
+   11, 10, 10, 19, 17, 11,  7, 11, 11,  4, 10,  4, 17,  8,  7, 11, // 0xe0
+   11, 10, 10,  4, 17, 11,  7, 11, 11,  6, 10,  4, 17,  4,  7, 11, // 0xf0
+];
+
+/// Returns the estimated T-states of the instruction at the address.
+pub fn instruction_cycles(machine: &MczMachine, address: u16) -> u32 {
+    let mut address = address;
+    let mut prefix_cycles = 0;
+    let mut opcode = machine.peek_unmonitored(address);
+    while opcode == 0xdd || opcode == 0xfd {
+        prefix_cycles += 4;
+        address = address.wrapping_add(1);
+        opcode = machine.peek_unmonitored(address);
+    }
+    let indexed = prefix_cycles != 0;
+
+    match opcode {
+        0xcb => {
+            let opcode = machine.peek_unmonitored(address.wrapping_add(if indexed {2} else {1}));
+            if indexed {
+                prefix_cycles + 19
+            } else if opcode & 0x07 == 0x06 {
+                15
+            } else {
+                8
+            }
+        },
+        0xed => {
+            let opcode = machine.peek_unmonitored(address.wrapping_add(1));
+            match opcode {
+                0xa0..=0xbf => 16,
+                0x40..=0x7f => match opcode & 0x07 {
+                    0 | 1 => 12,
+                    2 => 15,
+                    3 => 20,
+                    5 => 14,
+                    7 => if opcode >= 0x67 {18} else {9},
+                    _ => 8,
+                },
+                _ => 8,
+            }
+        },
+        _ => {
+            let cycles = CYCLES[opcode as usize] as u32;
+            let memory = match opcode {
+                0x34..=0x36 => true,
+                0x40..=0xbf => opcode & 0x07 == 0x06 || opcode & 0xf8 == 0x70,
+                _ => false,
+            };
+            if indexed && memory {
+                // (IX+d) and (IY+d) add the displacement calculation
+                prefix_cycles + cycles + 8
+            } else {
+                prefix_cycles + cycles
+            }
+        }
+    }
+}